@@ -0,0 +1,427 @@
+use std::{collections::BTreeMap, convert::TryFrom, error, fmt};
+use uuid::Uuid;
+
+use super::data::{AdvertisementData, Include, ScanResponseData};
+use crate::uuid::{to_sdp_short_uuid, SdpShortUuid};
+
+// Payload budgets, length and type bytes of every AD structure included
+pub const LEGACY_MAX_LENGTH: usize = 31;
+pub const EXTENDED_MAX_LENGTH: usize = 251;
+
+pub const FLAGS_LE_GENERAL_DISCOVERABLE: u8 = 0x02;
+pub const FLAGS_BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+
+const AD_TYPE_FLAGS: u8 = 0x01;
+const AD_TYPE_INCOMPLETE_UUIDS_16: u8 = 0x02;
+const AD_TYPE_COMPLETE_UUIDS_16: u8 = 0x03;
+const AD_TYPE_INCOMPLETE_UUIDS_32: u8 = 0x04;
+const AD_TYPE_COMPLETE_UUIDS_32: u8 = 0x05;
+const AD_TYPE_INCOMPLETE_UUIDS_128: u8 = 0x06;
+const AD_TYPE_COMPLETE_UUIDS_128: u8 = 0x07;
+const AD_TYPE_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_TYPE_TX_POWER_LEVEL: u8 = 0x0A;
+const AD_TYPE_SOLICIT_UUIDS_16: u8 = 0x14;
+const AD_TYPE_SOLICIT_UUIDS_128: u8 = 0x15;
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+const AD_TYPE_APPEARANCE: u8 = 0x19;
+const AD_TYPE_SOLICIT_UUIDS_32: u8 = 0x1F;
+const AD_TYPE_SERVICE_DATA_32: u8 = 0x20;
+const AD_TYPE_SERVICE_DATA_128: u8 = 0x21;
+const AD_TYPE_MANUFACTURER_DATA: u8 = 0xFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceUuids {
+    Uuids16(Vec<u16>),
+    Uuids32(Vec<u32>),
+    Uuids128(Vec<Uuid>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdStructure {
+    Flags(u8),
+    ServiceUuids { uuids: ServiceUuids, complete: bool },
+    ShortenedLocalName(String),
+    CompleteLocalName(String),
+    TxPowerLevel(i8),
+    SolicitUuids(ServiceUuids),
+    ServiceData { uuid: Uuid, data: Vec<u8> },
+    Appearance(u16),
+    ManufacturerData { company_id: u16, data: Vec<u8> },
+    Unknown { ad_type: u8, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdError {
+    PayloadTooLong { length: usize, max_length: usize },
+    StructureTooLong { ad_type: u8, length: usize },
+    Truncated { offset: usize },
+    Malformed { ad_type: u8 },
+}
+
+impl fmt::Display for AdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdError::PayloadTooLong { length, max_length } => {
+                write!(f, "payload {} bytes > {}", length, max_length)
+            }
+            AdError::StructureTooLong { ad_type, length } => write!(
+                f,
+                "AD structure 0x{:02X} has {} bytes of data, at most 254 fit",
+                ad_type, length
+            ),
+            AdError::Truncated { offset } => {
+                write!(f, "AD structure at offset {} is truncated", offset)
+            }
+            AdError::Malformed { ad_type } => {
+                write!(f, "AD structure 0x{:02X} is malformed", ad_type)
+            }
+        }
+    }
+}
+
+impl error::Error for AdError {}
+
+impl AdStructure {
+    pub fn ad_type(&self) -> u8 {
+        match self {
+            AdStructure::Flags(_) => AD_TYPE_FLAGS,
+            AdStructure::ServiceUuids { uuids, complete } => match (uuids, complete) {
+                (ServiceUuids::Uuids16(_), false) => AD_TYPE_INCOMPLETE_UUIDS_16,
+                (ServiceUuids::Uuids16(_), true) => AD_TYPE_COMPLETE_UUIDS_16,
+                (ServiceUuids::Uuids32(_), false) => AD_TYPE_INCOMPLETE_UUIDS_32,
+                (ServiceUuids::Uuids32(_), true) => AD_TYPE_COMPLETE_UUIDS_32,
+                (ServiceUuids::Uuids128(_), false) => AD_TYPE_INCOMPLETE_UUIDS_128,
+                (ServiceUuids::Uuids128(_), true) => AD_TYPE_COMPLETE_UUIDS_128,
+            },
+            AdStructure::ShortenedLocalName(_) => AD_TYPE_SHORTENED_LOCAL_NAME,
+            AdStructure::CompleteLocalName(_) => AD_TYPE_COMPLETE_LOCAL_NAME,
+            AdStructure::TxPowerLevel(_) => AD_TYPE_TX_POWER_LEVEL,
+            AdStructure::SolicitUuids(uuids) => match uuids {
+                ServiceUuids::Uuids16(_) => AD_TYPE_SOLICIT_UUIDS_16,
+                ServiceUuids::Uuids32(_) => AD_TYPE_SOLICIT_UUIDS_32,
+                ServiceUuids::Uuids128(_) => AD_TYPE_SOLICIT_UUIDS_128,
+            },
+            AdStructure::ServiceData { uuid, .. } => match to_sdp_short_uuid(uuid) {
+                Some(short) if short <= u32::from(u16::MAX) => AD_TYPE_SERVICE_DATA_16,
+                Some(_) => AD_TYPE_SERVICE_DATA_32,
+                None => AD_TYPE_SERVICE_DATA_128,
+            },
+            AdStructure::Appearance(_) => AD_TYPE_APPEARANCE,
+            AdStructure::ManufacturerData { .. } => AD_TYPE_MANUFACTURER_DATA,
+            AdStructure::Unknown { ad_type, .. } => *ad_type,
+        }
+    }
+
+    // Encoded length, including the length and type bytes
+    pub fn encoded_len(&self) -> usize {
+        2 + self.data().len()
+    }
+
+    pub fn encode(&self, payload: &mut Vec<u8>) -> Result<(), AdError> {
+        let data = self.data();
+        let length = u8::try_from(data.len() + 1).map_err(|_| AdError::StructureTooLong {
+            ad_type: self.ad_type(),
+            length: data.len(),
+        })?;
+        payload.push(length);
+        payload.push(self.ad_type());
+        payload.extend(data);
+        Ok(())
+    }
+
+    fn data(&self) -> Vec<u8> {
+        match self {
+            AdStructure::Flags(flags) => vec![*flags],
+            AdStructure::ServiceUuids { uuids, .. } | AdStructure::SolicitUuids(uuids) => {
+                encode_uuids(uuids)
+            }
+            AdStructure::ShortenedLocalName(name) | AdStructure::CompleteLocalName(name) => {
+                name.as_bytes().to_vec()
+            }
+            AdStructure::TxPowerLevel(level) => level.to_le_bytes().to_vec(),
+            AdStructure::ServiceData { uuid, data } => {
+                let mut bytes = match to_sdp_short_uuid(uuid) {
+                    Some(short) => match u16::try_from(short) {
+                        Ok(short) => short.to_le_bytes().to_vec(),
+                        Err(_) => short.to_le_bytes().to_vec(),
+                    },
+                    None => uuid_to_le_bytes(uuid),
+                };
+                bytes.extend(data);
+                bytes
+            }
+            AdStructure::Appearance(appearance) => appearance.to_le_bytes().to_vec(),
+            AdStructure::ManufacturerData { company_id, data } => {
+                let mut bytes = company_id.to_le_bytes().to_vec();
+                bytes.extend(data);
+                bytes
+            }
+            AdStructure::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    fn decode(ad_type: u8, data: &[u8]) -> Result<Self, AdError> {
+        let malformed = || AdError::Malformed { ad_type };
+        let structure = match ad_type {
+            AD_TYPE_FLAGS => AdStructure::Flags(*data.first().ok_or_else(malformed)?),
+            AD_TYPE_INCOMPLETE_UUIDS_16
+            | AD_TYPE_COMPLETE_UUIDS_16
+            | AD_TYPE_INCOMPLETE_UUIDS_32
+            | AD_TYPE_COMPLETE_UUIDS_32
+            | AD_TYPE_INCOMPLETE_UUIDS_128
+            | AD_TYPE_COMPLETE_UUIDS_128 => {
+                let width = match ad_type {
+                    AD_TYPE_INCOMPLETE_UUIDS_16 | AD_TYPE_COMPLETE_UUIDS_16 => 2,
+                    AD_TYPE_INCOMPLETE_UUIDS_32 | AD_TYPE_COMPLETE_UUIDS_32 => 4,
+                    _ => 16,
+                };
+                AdStructure::ServiceUuids {
+                    uuids: decode_uuids(data, width).ok_or_else(malformed)?,
+                    complete: ad_type % 2 == 1,
+                }
+            }
+            AD_TYPE_SHORTENED_LOCAL_NAME => AdStructure::ShortenedLocalName(
+                String::from_utf8(data.to_vec()).map_err(|_| malformed())?,
+            ),
+            AD_TYPE_COMPLETE_LOCAL_NAME => AdStructure::CompleteLocalName(
+                String::from_utf8(data.to_vec()).map_err(|_| malformed())?,
+            ),
+            AD_TYPE_TX_POWER_LEVEL => {
+                AdStructure::TxPowerLevel(*data.first().ok_or_else(malformed)? as i8)
+            }
+            AD_TYPE_SOLICIT_UUIDS_16 => {
+                AdStructure::SolicitUuids(decode_uuids(data, 2).ok_or_else(malformed)?)
+            }
+            AD_TYPE_SOLICIT_UUIDS_32 => {
+                AdStructure::SolicitUuids(decode_uuids(data, 4).ok_or_else(malformed)?)
+            }
+            AD_TYPE_SOLICIT_UUIDS_128 => {
+                AdStructure::SolicitUuids(decode_uuids(data, 16).ok_or_else(malformed)?)
+            }
+            AD_TYPE_SERVICE_DATA_16 | AD_TYPE_SERVICE_DATA_32 | AD_TYPE_SERVICE_DATA_128 => {
+                let width = match ad_type {
+                    AD_TYPE_SERVICE_DATA_16 => 2,
+                    AD_TYPE_SERVICE_DATA_32 => 4,
+                    _ => 16,
+                };
+                if data.len() < width {
+                    return Err(malformed());
+                }
+                let (uuid, data) = data.split_at(width);
+                AdStructure::ServiceData {
+                    uuid: uuid_from_le_bytes(uuid).ok_or_else(malformed)?,
+                    data: data.to_vec(),
+                }
+            }
+            AD_TYPE_APPEARANCE if data.len() == 2 => {
+                AdStructure::Appearance(u16::from_le_bytes([data[0], data[1]]))
+            }
+            AD_TYPE_APPEARANCE => return Err(malformed()),
+            AD_TYPE_MANUFACTURER_DATA if data.len() >= 2 => AdStructure::ManufacturerData {
+                company_id: u16::from_le_bytes([data[0], data[1]]),
+                data: data[2..].to_vec(),
+            },
+            AD_TYPE_MANUFACTURER_DATA => return Err(malformed()),
+            _ => AdStructure::Unknown {
+                ad_type,
+                data: data.to_vec(),
+            },
+        };
+        Ok(structure)
+    }
+}
+
+pub fn encode(structures: &[AdStructure]) -> Result<Vec<u8>, AdError> {
+    let mut payload = Vec::with_capacity(encoded_len(structures));
+    for structure in structures {
+        structure.encode(&mut payload)?;
+    }
+    Ok(payload)
+}
+
+pub fn encoded_len(structures: &[AdStructure]) -> usize {
+    structures.iter().map(AdStructure::encoded_len).sum()
+}
+
+// Checks that the structures fit in `max_length` bytes, returning the payload length
+pub fn validate(structures: &[AdStructure], max_length: usize) -> Result<usize, AdError> {
+    let length = encoded_len(structures);
+    if length > max_length {
+        return Err(AdError::PayloadTooLong { length, max_length });
+    }
+    Ok(length)
+}
+
+pub fn decode(payload: &[u8]) -> Result<Vec<AdStructure>, AdError> {
+    let mut structures = vec![];
+    let mut offset = 0;
+    while offset < payload.len() {
+        let length = usize::from(payload[offset]);
+        // A zero length marks the start of the unused part of the payload
+        if length == 0 {
+            break;
+        }
+        let end = offset + 1 + length;
+        if end > payload.len() {
+            return Err(AdError::Truncated { offset });
+        }
+        structures.push(AdStructure::decode(
+            payload[offset + 1],
+            &payload[offset + 2..end],
+        )?);
+        offset = end;
+    }
+    Ok(structures)
+}
+
+impl AdvertisementData {
+    // Structures the Bluetooth stack will put in the advertising payload, not
+    // including the flags it may add
+    pub fn ad_structures(&self) -> Vec<AdStructure> {
+        let mut structures = common_structures(
+            &self.local_name,
+            &self.service_uuids,
+            &self.solicit_uuids,
+            &self.service_data,
+            &self.manufacturer_data,
+        );
+        if self.appearance.is_some() || self.includes.contains(&Include::Appearance) {
+            structures.push(AdStructure::Appearance(self.appearance.unwrap_or(0)));
+        }
+        if self.includes.contains(&Include::TxPower) {
+            let level = self.tx_power.map_or(0, |tx_power| {
+                tx_power.max(i8::MIN.into()).min(i8::MAX.into()) as i8
+            });
+            structures.push(AdStructure::TxPowerLevel(level));
+        }
+        structures
+    }
+}
+
+impl ScanResponseData {
+    pub fn ad_structures(&self) -> Vec<AdStructure> {
+        common_structures(
+            &self.local_name,
+            &self.service_uuids,
+            &self.solicit_uuids,
+            &self.service_data,
+            &self.manufacturer_data,
+        )
+    }
+}
+
+fn common_structures(
+    local_name: &Option<String>,
+    service_uuids: &[Uuid],
+    solicit_uuids: &[Uuid],
+    service_data: &BTreeMap<Uuid, Vec<u8>>,
+    manufacturer_data: &BTreeMap<u16, Vec<u8>>,
+) -> Vec<AdStructure> {
+    let mut structures = vec![];
+    if let Some(local_name) = local_name {
+        structures.push(AdStructure::CompleteLocalName(local_name.clone()));
+    }
+    push_uuids(&mut structures, service_uuids, |uuids| {
+        AdStructure::ServiceUuids {
+            uuids,
+            complete: true,
+        }
+    });
+    push_uuids(&mut structures, solicit_uuids, AdStructure::SolicitUuids);
+    for (uuid, data) in service_data.iter() {
+        structures.push(AdStructure::ServiceData {
+            uuid: *uuid,
+            data: data.clone(),
+        });
+    }
+    for (company_id, data) in manufacturer_data.iter() {
+        structures.push(AdStructure::ManufacturerData {
+            company_id: *company_id,
+            data: data.clone(),
+        });
+    }
+    structures
+}
+
+fn push_uuids<F>(structures: &mut Vec<AdStructure>, uuids: &[Uuid], structure: F)
+where
+    F: Fn(ServiceUuids) -> AdStructure,
+{
+    let mut uuids16 = vec![];
+    let mut uuids32 = vec![];
+    let mut uuids128 = vec![];
+    for uuid in uuids {
+        match to_sdp_short_uuid(uuid) {
+            Some(short) => match u16::try_from(short) {
+                Ok(short) => uuids16.push(short),
+                Err(_) => uuids32.push(short),
+            },
+            None => uuids128.push(*uuid),
+        }
+    }
+    if !uuids16.is_empty() {
+        structures.push(structure(ServiceUuids::Uuids16(uuids16)));
+    }
+    if !uuids32.is_empty() {
+        structures.push(structure(ServiceUuids::Uuids32(uuids32)));
+    }
+    if !uuids128.is_empty() {
+        structures.push(structure(ServiceUuids::Uuids128(uuids128)));
+    }
+}
+
+fn encode_uuids(uuids: &ServiceUuids) -> Vec<u8> {
+    match uuids {
+        ServiceUuids::Uuids16(uuids) => uuids
+            .iter()
+            .flat_map(|uuid| uuid.to_le_bytes().to_vec())
+            .collect(),
+        ServiceUuids::Uuids32(uuids) => uuids
+            .iter()
+            .flat_map(|uuid| uuid.to_le_bytes().to_vec())
+            .collect(),
+        ServiceUuids::Uuids128(uuids) => uuids.iter().flat_map(uuid_to_le_bytes).collect(),
+    }
+}
+
+fn decode_uuids(data: &[u8], width: usize) -> Option<ServiceUuids> {
+    let chunks = data.chunks_exact(width);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    Some(match width {
+        2 => ServiceUuids::Uuids16(
+            chunks
+                .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+                .collect(),
+        ),
+        4 => ServiceUuids::Uuids32(
+            chunks
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect(),
+        ),
+        _ => ServiceUuids::Uuids128(chunks.filter_map(uuid_from_le_bytes).collect()),
+    })
+}
+
+// 128-bit UUIDs go over the air in little-endian order
+fn uuid_to_le_bytes(uuid: &Uuid) -> Vec<u8> {
+    uuid.as_bytes().iter().rev().cloned().collect()
+}
+
+fn uuid_from_le_bytes(bytes: &[u8]) -> Option<Uuid> {
+    match bytes.len() {
+        2 => Some(Uuid::from_sdp_short_uuid(u16::from_le_bytes([
+            bytes[0], bytes[1],
+        ]))),
+        4 => Some(Uuid::from_sdp_short_uuid(u32::from_le_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3],
+        ]))),
+        16 => {
+            let reversed: Vec<u8> = bytes.iter().rev().cloned().collect();
+            Uuid::from_slice(&reversed).ok()
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,22 @@
+use super::data::{Include, SecondaryChannel};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdvertisingCapabilities {
+    pub supported_instances: u8,
+    pub active_instances: u8,
+    pub supported_includes: Vec<Include>,
+    pub supported_secondary_channels: Vec<SecondaryChannel>,
+    // Backend specific feature names, e.g. `CanSetTxPower` or `HardwareOffload` on BlueZ
+    pub supported_features: Vec<String>,
+    // Not every backend reports the limits below
+    pub max_advertising_length: Option<u8>,
+    pub max_scan_response_length: Option<u8>,
+    pub min_tx_power: Option<i16>,
+    pub max_tx_power: Option<i16>,
+}
+
+impl AdvertisingCapabilities {
+    pub fn supports_extended_advertising(&self) -> bool {
+        !self.supported_secondary_channels.is_empty()
+    }
+}
@@ -0,0 +1,57 @@
+use std::{collections::BTreeMap, time::Duration};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Include {
+    TxPower,
+    Appearance,
+    LocalName,
+}
+
+// PHY carrying extended advertisements, which lifts the 31 byte payload limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecondaryChannel {
+    OneM,
+    TwoM,
+    Coded,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AdvertisementData {
+    pub local_name: Option<String>,
+    pub service_uuids: Vec<Uuid>,
+    pub solicit_uuids: Vec<Uuid>,
+    pub manufacturer_data: BTreeMap<u16, Vec<u8>>,
+    pub service_data: BTreeMap<Uuid, Vec<u8>>,
+    pub appearance: Option<u16>,
+    pub tx_power: Option<i16>,
+    pub includes: Vec<Include>,
+    pub discoverable: Option<bool>,
+    pub discoverable_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub duration: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub max_interval: Option<Duration>,
+    pub scan_response: Option<ScanResponseData>,
+    pub secondary_channel: Option<SecondaryChannel>,
+}
+
+// Sent only to centrals that actively scan, in a separate 31 byte payload
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanResponseData {
+    pub local_name: Option<String>,
+    pub service_uuids: Vec<Uuid>,
+    pub solicit_uuids: Vec<Uuid>,
+    pub manufacturer_data: BTreeMap<u16, Vec<u8>>,
+    pub service_data: BTreeMap<Uuid, Vec<u8>>,
+}
+
+impl AdvertisementData {
+    pub fn new<T: Into<String>>(local_name: T, service_uuids: &[Uuid]) -> Self {
+        AdvertisementData {
+            local_name: Some(local_name.into()),
+            service_uuids: service_uuids.to_vec(),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::{error, fmt, time::Duration};
+use uuid::Uuid;
+
+use super::data::AdvertisementData;
+use crate::uuid::SdpShortUuid;
+
+pub const EDDYSTONE_SERVICE_UUID: u16 = 0xFEAA;
+
+const FRAME_TYPE_UID: u8 = 0x00;
+const FRAME_TYPE_URL: u8 = 0x10;
+const FRAME_TYPE_TLM: u8 = 0x20;
+const FRAME_TYPE_EID: u8 = 0x30;
+
+const TLM_VERSION: u8 = 0x00;
+const TLM_TEMPERATURE_UNSUPPORTED: i16 = -0x8000;
+
+const URL_MAX_ENCODED_LENGTH: usize = 17;
+
+const URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+
+const URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EddystoneError {
+    UnsupportedUrlScheme,
+    UrlTooLong { length: usize },
+    InvalidUrlCharacter(char),
+}
+
+impl fmt::Display for EddystoneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EddystoneError::UnsupportedUrlScheme => write!(
+                f,
+                "URL must start with one of http://www., https://www., http:// or https://"
+            ),
+            EddystoneError::UrlTooLong { length } => write!(
+                f,
+                "URL encodes to {} bytes > {}",
+                length, URL_MAX_ENCODED_LENGTH
+            ),
+            EddystoneError::InvalidUrlCharacter(character) => {
+                write!(f, "URL contains non-graphic character {:?}", character)
+            }
+        }
+    }
+}
+
+impl error::Error for EddystoneError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EddystoneUid {
+    // Calibrated TX power at 0 m in dBm
+    pub tx_power: i8,
+    pub namespace: [u8; 10],
+    pub instance: [u8; 6],
+}
+
+impl EddystoneUid {
+    pub fn new(tx_power: i8, namespace: [u8; 10], instance: [u8; 6]) -> Self {
+        EddystoneUid {
+            tx_power,
+            namespace,
+            instance,
+        }
+    }
+
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame = vec![FRAME_TYPE_UID, self.tx_power as u8];
+        frame.extend_from_slice(&self.namespace);
+        frame.extend_from_slice(&self.instance);
+        // Reserved for future use
+        frame.extend_from_slice(&[0x00, 0x00]);
+        frame
+    }
+
+    pub fn advertisement_data(&self) -> AdvertisementData {
+        advertisement_data(self.frame())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EddystoneUrl {
+    pub tx_power: i8,
+    encoded_url: Vec<u8>,
+}
+
+impl EddystoneUrl {
+    pub fn new(tx_power: i8, url: &str) -> Result<Self, EddystoneError> {
+        Ok(EddystoneUrl {
+            tx_power,
+            encoded_url: encode_url(url)?,
+        })
+    }
+
+    // Scheme prefix followed by the compressed remainder of the URL
+    pub fn encoded_url(&self) -> &[u8] {
+        &self.encoded_url
+    }
+
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame = vec![FRAME_TYPE_URL, self.tx_power as u8];
+        frame.extend_from_slice(&self.encoded_url);
+        frame
+    }
+
+    pub fn advertisement_data(&self) -> AdvertisementData {
+        advertisement_data(self.frame())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EddystoneTlm {
+    // Battery voltage in mV, 0 when not battery powered
+    pub battery_voltage: u16,
+    // Beacon temperature in degrees Celsius, if it has a sensor
+    pub temperature: Option<f32>,
+    pub advertisement_count: u32,
+    pub uptime: Duration,
+}
+
+impl EddystoneTlm {
+    pub fn new(
+        battery_voltage: u16,
+        temperature: Option<f32>,
+        advertisement_count: u32,
+        uptime: Duration,
+    ) -> Self {
+        EddystoneTlm {
+            battery_voltage,
+            temperature,
+            advertisement_count,
+            uptime,
+        }
+    }
+
+    pub fn frame(&self) -> Vec<u8> {
+        // Temperature is a signed 8.8 fixed point number
+        let temperature = self
+            .temperature
+            .map_or(TLM_TEMPERATURE_UNSUPPORTED, |temperature| {
+                (temperature * 256.0)
+                    .round()
+                    .max(f32::from(i16::MIN + 1))
+                    .min(f32::from(i16::MAX)) as i16
+            });
+        // Uptime is counted in 0.1 second steps
+        let uptime = (self.uptime.as_millis() / 100).min(u128::from(u32::MAX)) as u32;
+
+        let mut frame = vec![FRAME_TYPE_TLM, TLM_VERSION];
+        frame.extend_from_slice(&self.battery_voltage.to_be_bytes());
+        frame.extend_from_slice(&temperature.to_be_bytes());
+        frame.extend_from_slice(&self.advertisement_count.to_be_bytes());
+        frame.extend_from_slice(&uptime.to_be_bytes());
+        frame
+    }
+
+    pub fn advertisement_data(&self) -> AdvertisementData {
+        advertisement_data(self.frame())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EddystoneEid {
+    pub tx_power: i8,
+    pub ephemeral_id: [u8; 8],
+}
+
+impl EddystoneEid {
+    pub fn new(tx_power: i8, ephemeral_id: [u8; 8]) -> Self {
+        EddystoneEid {
+            tx_power,
+            ephemeral_id,
+        }
+    }
+
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame = vec![FRAME_TYPE_EID, self.tx_power as u8];
+        frame.extend_from_slice(&self.ephemeral_id);
+        frame
+    }
+
+    pub fn advertisement_data(&self) -> AdvertisementData {
+        advertisement_data(self.frame())
+    }
+}
+
+fn advertisement_data(frame: Vec<u8>) -> AdvertisementData {
+    let uuid = Uuid::from_sdp_short_uuid(EDDYSTONE_SERVICE_UUID);
+    let mut advertisement_data = AdvertisementData::default();
+    advertisement_data.service_uuids.push(uuid);
+    advertisement_data.service_data.insert(uuid, frame);
+    advertisement_data
+}
+
+fn encode_url(url: &str) -> Result<Vec<u8>, EddystoneError> {
+    // Prefer the longer schemes, `http://` is a prefix of `http://www.`
+    let (scheme, mut rest) = URL_SCHEMES
+        .iter()
+        .enumerate()
+        .filter_map(|(code, scheme)| url.strip_prefix(scheme).map(|rest| (code, rest)))
+        .min_by_key(|(_, rest)| rest.len())
+        .ok_or(EddystoneError::UnsupportedUrlScheme)?;
+
+    let mut encoded = vec![scheme as u8];
+    while let Some(character) = rest.chars().next() {
+        if let Some((code, expansion)) = URL_EXPANSIONS
+            .iter()
+            .enumerate()
+            .find(|(_, expansion)| rest.starts_with(*expansion))
+        {
+            encoded.push(code as u8);
+            rest = &rest[expansion.len()..];
+        } else if character.is_ascii_graphic() {
+            encoded.push(character as u8);
+            rest = &rest[1..];
+        } else {
+            return Err(EddystoneError::InvalidUrlCharacter(character));
+        }
+    }
+
+    // The scheme prefix does not count against the URL length
+    if encoded.len() - 1 > URL_MAX_ENCODED_LENGTH {
+        return Err(EddystoneError::UrlTooLong {
+            length: encoded.len() - 1,
+        });
+    }
+    Ok(encoded)
+}
@@ -0,0 +1,54 @@
+use uuid::Uuid;
+
+use super::data::AdvertisementData;
+
+pub const APPLE_COMPANY_ID: u16 = 0x004C;
+
+const IBEACON_TYPE: u8 = 0x02;
+const IBEACON_LENGTH: u8 = 0x15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IBeacon {
+    pub proximity_uuid: Uuid,
+    pub major: u16,
+    pub minor: u16,
+    // RSSI in dBm measured at one metre from the beacon
+    pub measured_power: i8,
+}
+
+impl IBeacon {
+    pub fn new(proximity_uuid: Uuid, major: u16, minor: u16, measured_power: i8) -> Self {
+        IBeacon {
+            proximity_uuid,
+            major,
+            minor,
+            measured_power,
+        }
+    }
+
+    // Manufacturer specific data following the Apple company identifier
+    pub fn manufacturer_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(usize::from(IBEACON_LENGTH) + 2);
+        data.push(IBEACON_TYPE);
+        data.push(IBEACON_LENGTH);
+        data.extend_from_slice(self.proximity_uuid.as_bytes());
+        data.extend_from_slice(&self.major.to_be_bytes());
+        data.extend_from_slice(&self.minor.to_be_bytes());
+        data.extend_from_slice(&self.measured_power.to_be_bytes());
+        data
+    }
+
+    pub fn advertisement_data(&self) -> AdvertisementData {
+        let mut advertisement_data = AdvertisementData::default();
+        advertisement_data
+            .manufacturer_data
+            .insert(APPLE_COMPANY_ID, self.manufacturer_data());
+        advertisement_data
+    }
+}
+
+impl From<IBeacon> for AdvertisementData {
+    fn from(ibeacon: IBeacon) -> Self {
+        ibeacon.advertisement_data()
+    }
+}
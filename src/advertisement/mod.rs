@@ -0,0 +1,7 @@
+//! Advertising data shared by every backend
+
+pub mod ad_structure;
+pub mod capabilities;
+pub mod data;
+pub mod eddystone;
+pub mod ibeacon;
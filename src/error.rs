@@ -1,4 +1,4 @@
-use std::{error, fmt};
+use std::{error, fmt, sync::Arc};
 
 #[derive(Debug, Clone)]
 pub enum ErrorType {
@@ -26,26 +26,76 @@ impl fmt::Display for ErrorType {
 
 impl error::Error for ErrorType {}
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    AdapterNotFound,
+    NotPowered,
+    AlreadyRegistered,
+    NotRegistered,
+    PermissionDenied,
+    Timeout,
+    InvalidDefinition,
+    NotSupported,
+    ResourcesExhausted,
+    BackendDisconnected,
+    // Any other failure, named the way the platform names it (e.g. a D-Bus error name)
+    Platform(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::AdapterNotFound => write!(f, "Adapter not found"),
+            ErrorKind::NotPowered => write!(f, "Adapter not powered"),
+            ErrorKind::AlreadyRegistered => write!(f, "Already registered"),
+            ErrorKind::NotRegistered => write!(f, "Not registered"),
+            ErrorKind::PermissionDenied => write!(f, "Permission denied"),
+            ErrorKind::Timeout => write!(f, "Timed out"),
+            ErrorKind::InvalidDefinition => write!(f, "Invalid definition"),
+            ErrorKind::NotSupported => write!(f, "Not supported"),
+            ErrorKind::ResourcesExhausted => write!(f, "Resources exhausted"),
+            ErrorKind::BackendDisconnected => write!(f, "Backend disconnected"),
+            ErrorKind::Platform(name) => write!(f, "{}", name),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Error {
-    name: String,
+    kind: ErrorKind,
     description: String,
-    combined_description: String,
     error_type: ErrorType,
+    source: Option<Arc<dyn error::Error + Send + Sync>>,
 }
 
 impl Error {
-    pub fn new<T: Into<String>>(name: T, description: T, error_type: ErrorType) -> Self {
-        let name: String = name.into();
-        let description: String = description.into();
-        let combined_description = format!("{}: {}", name, description);
+    pub fn new<T: Into<String>>(kind: ErrorKind, description: T, error_type: ErrorType) -> Self {
         Error {
-            name,
-            description,
-            combined_description,
+            kind,
+            description: description.into(),
             error_type,
+            source: None,
         }
     }
+
+    // Keeps the backend's own error around as `source()`
+    pub fn with_source<E: error::Error + Send + Sync + 'static>(mut self, source: E) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn error_type(&self) -> &ErrorType {
+        &self.error_type
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
 }
 
 impl fmt::Display for Error {
@@ -54,17 +104,15 @@ impl fmt::Display for Error {
         write!(
             f,
             "**Bluster {} Error**\n\n\t{}:\n\t\t{}",
-            error_type, self.name, self.description,
+            error_type, self.kind, self.description,
         )
     }
 }
 
 impl error::Error for Error {
-    fn description(self: &Self) -> &str {
-        &self.combined_description
-    }
-
     fn source(self: &Self) -> Option<&(dyn error::Error + 'static)> {
-        Some(&self.error_type)
+        self.source
+            .as_ref()
+            .map(|source| &**source as &(dyn error::Error + 'static))
     }
 }
@@ -1,7 +1,13 @@
-use super::{descriptor::Descriptor, event::EventSender};
+use super::{
+    descriptor::Descriptor,
+    event::{EventSender, LongValues, Response, ResponseTimeout},
+    handler::{CharacteristicHandler, LazyHandler},
+    notification::NotificationQueue,
+};
 use std::{
     collections::HashSet,
     hash::{Hash, Hasher},
+    time::Duration,
 };
 use uuid::Uuid;
 
@@ -11,6 +17,10 @@ pub struct Characteristic {
     pub(crate) properties: Properties,
     pub(crate) value: Option<Vec<u8>>,
     pub(crate) descriptors: HashSet<Descriptor>,
+    pub(crate) notification_queue: NotificationQueue,
+    pub(crate) response_timeout: Option<ResponseTimeout>,
+    pub(crate) long_values: Option<LongValues>,
+    pub(crate) handler: Option<LazyHandler>,
 }
 
 impl Characteristic {
@@ -25,8 +35,33 @@ impl Characteristic {
             properties,
             value,
             descriptors,
+            notification_queue: NotificationQueue::default(),
+            response_timeout: None,
+            long_values: None,
+            handler: None,
         }
     }
+
+    pub fn with_notification_queue(mut self, notification_queue: NotificationQueue) -> Self {
+        self.notification_queue = notification_queue;
+        self
+    }
+
+    // Answers reads and writes with `response` when the handler takes longer than `duration`
+    pub fn with_response_timeout(mut self, duration: Duration, response: Response) -> Self {
+        self.response_timeout = Some(ResponseTimeout { duration, response });
+        self
+    }
+
+    pub fn with_long_values(mut self, long_values: LongValues) -> Self {
+        self.long_values = Some(long_values);
+        self
+    }
+
+    pub fn with_handler<H: CharacteristicHandler>(mut self, handler: H) -> Self {
+        self.handler = Some(LazyHandler::new(handler));
+        self
+    }
 }
 
 impl_uuid_hash_eq!(Characteristic);
@@ -1,5 +1,11 @@
-use super::event::EventSender;
-use std::hash::{Hash, Hasher};
+use super::{
+    event::{EventSender, LongValues, Response, ResponseTimeout},
+    handler::{CharacteristicHandler, LazyHandler},
+};
+use std::{
+    hash::{Hash, Hasher},
+    time::Duration,
+};
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
@@ -7,6 +13,9 @@ pub struct Descriptor {
     pub(crate) uuid: Uuid,
     pub(crate) properties: Properties,
     pub(crate) value: Option<Vec<u8>>,
+    pub(crate) response_timeout: Option<ResponseTimeout>,
+    pub(crate) long_values: Option<LongValues>,
+    pub(crate) handler: Option<LazyHandler>,
 }
 
 impl Descriptor {
@@ -15,8 +24,27 @@ impl Descriptor {
             uuid,
             properties,
             value,
+            response_timeout: None,
+            long_values: None,
+            handler: None,
         }
     }
+
+    // Answers reads and writes with `response` when the handler takes longer than `duration`
+    pub fn with_response_timeout(mut self, duration: Duration, response: Response) -> Self {
+        self.response_timeout = Some(ResponseTimeout { duration, response });
+        self
+    }
+
+    pub fn with_long_values(mut self, long_values: LongValues) -> Self {
+        self.long_values = Some(long_values);
+        self
+    }
+
+    pub fn with_handler<H: CharacteristicHandler>(mut self, handler: H) -> Self {
+        self.handler = Some(LazyHandler::new(handler));
+        self
+    }
 }
 
 impl_uuid_hash_eq!(Descriptor);
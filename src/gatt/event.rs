@@ -1,14 +1,115 @@
-use futures::channel::{mpsc, oneshot};
+use futures::{
+    channel::{mpsc, oneshot},
+    lock::Mutex,
+    prelude::*,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-pub type EventSender = mpsc::Sender<Event>;
+use super::notification::Notifier;
+
+pub type EventReceiver = mpsc::Receiver<Event>;
 pub type ResponseSender = oneshot::Sender<Response>;
 
+// What to do with a request when the handler's channel is full
+#[derive(Debug, Clone)]
+pub enum Overflow {
+    // Hold the request until the handler makes room
+    Wait,
+    // Answer right away with this response
+    Reject(Response),
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+}
+
+// Carries requests to a handler. Clones share the channel, overflow behaviour and counters.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    // A single sender, as every clone of an `mpsc::Sender` gets a slot of its own and would
+    // never see the channel as full
+    sender: Arc<Mutex<mpsc::Sender<Event>>>,
+    overflow: Overflow,
+    counters: Arc<Counters>,
+}
+
+// Creates the channel a characteristic or descriptor hands its requests to, holding up to
+// `capacity` of them (at least one) while the handler is busy. Unlike `mpsc::channel`, clones
+// of the sender don't add to that.
+pub fn channel(capacity: usize) -> (EventSender, EventReceiver) {
+    // The sender's own slot holds one more than the buffer
+    let (sender, receiver) = mpsc::channel(capacity.saturating_sub(1));
+    (EventSender::from(sender), receiver)
+}
+
+impl From<mpsc::Sender<Event>> for EventSender {
+    fn from(sender: mpsc::Sender<Event>) -> Self {
+        EventSender {
+            sender: Arc::new(Mutex::new(sender)),
+            overflow: Overflow::Wait,
+            counters: Arc::default(),
+        }
+    }
+}
+
+impl EventSender {
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    // Requests answered with the overflow response because the channel was full
+    pub fn rejected(&self) -> u64 {
+        self.counters.rejected.load(Ordering::Relaxed)
+    }
+
+    // Events lost because the receiver was gone
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) async fn deliver(&self, event: Event) -> Result<(), Delivery> {
+        let mut sender = self.sender.lock().await;
+        let result = match &self.overflow {
+            Overflow::Wait => sender.send(event).await,
+            Overflow::Reject(response) => match sender.try_send(event) {
+                Err(err) if err.is_full() => {
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(Delivery::Rejected(response.clone()));
+                }
+                result => result.map_err(|err| err.into_send_error()),
+            },
+        };
+        result.map_err(|_| {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            Delivery::Dropped
+        })
+    }
+}
+
+// Why an event didn't reach its handler
+#[derive(Debug)]
+pub(crate) enum Delivery {
+    Rejected(Response),
+    Dropped,
+}
+
 #[derive(Debug)]
 pub enum Event {
     ReadRequest(ReadRequest),
     WriteRequest(WriteRequest),
     NotifySubscribe(NotifySubscribe),
     NotifyUnsubscribe,
+    // The central acknowledged an indication
+    IndicationConfirmed,
 }
 
 #[derive(Debug)]
@@ -27,7 +128,7 @@ pub struct WriteRequest {
 
 #[derive(Debug, Clone)]
 pub struct NotifySubscribe {
-    pub notification: mpsc::Sender<Vec<u8>>,
+    pub notification: Notifier,
 }
 
 #[derive(Debug, Clone)]
@@ -36,4 +137,39 @@ pub enum Response {
     InvalidOffset,
     InvalidAttributeLength,
     UnlikelyError,
+    // The request can't be handled right now and may be retried
+    Busy,
+}
+
+// How long a handler gets to answer a read or write, and the response sent on its behalf after that
+#[derive(Debug, Clone)]
+pub struct ResponseTimeout {
+    pub duration: Duration,
+    pub response: Response,
+}
+
+// Lets the library take care of offsets: long reads are answered from the full value, read once
+// per client and kept until it hasn't asked for a part for `settle`, and the parts of a long
+// write are put back together and handed over as one write request once no more have come in
+// for `settle`, or before the client's next read or write. Handlers then only ever see offset 0.
+//
+// What opting in costs: every part of a long write is accepted as it comes in, so the central
+// is told the write succeeded even when the handler then refuses the whole value. The refusal
+// only shows up locally, as `PeripheralEvent::LongWriteFailed`. Long writes from a client BlueZ
+// doesn't name are refused, since their parts can't be told apart from other clients'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LongValues {
+    // Longer values are refused with `InvalidAttributeLength`
+    pub max_length: usize,
+    pub settle: Duration,
+}
+
+impl Default for LongValues {
+    fn default() -> Self {
+        LongValues {
+            // The longest value ATT allows
+            max_length: 512,
+            settle: Duration::from_millis(100),
+        }
+    }
 }
@@ -13,6 +13,21 @@ macro_rules! _write_type {
                     Write::WithoutResponse(event_sender) => event_sender,
                 }
             }
+
+            pub(crate) fn declared(access: WriteAccess, event_sender: $event_sender) -> Self {
+                match access {
+                    WriteAccess::WithResponse(access) => {
+                        Write::WithResponse($secure::declared(access, event_sender))
+                    }
+                    WriteAccess::WithoutResponse => Write::WithoutResponse(event_sender),
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum WriteAccess {
+            WithResponse(Access),
+            WithoutResponse,
         }
     };
     (WriteWithResponse, $event_sender:ident, $secure:ident) => {
@@ -23,8 +38,14 @@ macro_rules! _write_type {
             pub fn sender(self: Self) -> $event_sender {
                 self.0.sender()
             }
+
+            pub(crate) fn declared(access: WriteAccess, event_sender: $event_sender) -> Self {
+                Write($secure::declared(access, event_sender))
+            }
         }
 
+        pub type WriteAccess = Access;
+
         impl std::ops::Deref for Write {
             type Target = $secure;
 
@@ -60,6 +81,21 @@ macro_rules! _properties {
             pub fn is_read_only(self: &Self) -> bool {
                 self.read.is_some() && self.write.is_none()
             }
+
+            // Only says which properties there are, for a handler to answer them. Until one is
+            // set with `with_handler`, requests to them fail.
+            pub fn declare(
+                read: Option<Access>,
+                write: Option<WriteAccess>,
+                $($member: bool,)*
+            ) -> Self {
+                let (event_sender, _) = crate::gatt::event::channel(1);
+                Properties {
+                    read: read.map(|access| Read(Secure::declared(access, event_sender.clone()))),
+                    write: write.map(|access| Write::declared(access, event_sender.clone())),
+                    $($member: if $member { Some(event_sender.clone()) } else { None },)*
+                }
+            }
         }
 
         #[derive(Debug, Clone)]
@@ -68,8 +104,7 @@ macro_rules! _properties {
         impl Read {
             pub fn sender(self: Self) -> $event_sender {
                 self.0.sender()
-            }
-        }
+            }        }
 
         impl std::ops::Deref for Read {
             type Target = Secure;
@@ -92,6 +127,20 @@ macro_rules! _properties {
                     Secure::Insecure(event_sender) => event_sender,
                 }
             }
+
+            pub(crate) fn declared(access: Access, event_sender: $event_sender) -> Self {
+                match access {
+                    Access::Secure => Secure::Secure(event_sender),
+                    Access::Insecure => Secure::Insecure(event_sender),
+                }
+            }
+        }
+
+        // Which of `Secure`'s variants a property declared with `Properties::declare` gets
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Access {
+            Secure,
+            Insecure,
         }
     }
 }
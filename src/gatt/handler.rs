@@ -0,0 +1,168 @@
+use async_trait::async_trait;
+use futures::prelude::*;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    event::{self, Event, EventSender, Response},
+    notification::Notifier,
+};
+use crate::lock::LockExt;
+
+// How a central wrote the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteKind {
+    // Write request, answered once the handler returns
+    Request,
+    // Write command, the central doesn't wait for an answer
+    Command,
+    // Part of a long write, starting at `offset`
+    Prepared { offset: u16 },
+}
+
+// What a handler knows about its characteristic or descriptor between calls
+#[derive(Debug, Default)]
+pub struct Context {
+    notifier: Option<Notifier>,
+}
+
+impl Context {
+    // The current subscription, if a central is subscribed
+    pub fn notifier(&self) -> Option<&Notifier> {
+        self.notifier.as_ref()
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.notifier
+            .as_ref()
+            .map(Notifier::is_subscribed)
+            .unwrap_or(false)
+    }
+}
+
+// Answers the requests for one characteristic or descriptor, in place of a loop over `Event`s.
+// Descriptors only ever get reads and writes.
+#[async_trait]
+pub trait CharacteristicHandler: Send + Sync + 'static {
+    async fn on_read(&self, _ctx: &Context, _offset: u16) -> Response {
+        Response::UnlikelyError
+    }
+
+    async fn on_write(&self, _ctx: &Context, _data: Vec<u8>, _kind: WriteKind) -> Response {
+        Response::UnlikelyError
+    }
+
+    // `ctx.notifier()` holds the new subscription
+    async fn on_subscribe(&self, _ctx: &Context) {}
+
+    async fn on_unsubscribe(&self, _ctx: &Context) {}
+
+    async fn on_indication_confirmed(&self, _ctx: &Context) {}
+}
+
+#[async_trait]
+impl<T: CharacteristicHandler + ?Sized> CharacteristicHandler for Arc<T> {
+    async fn on_read(&self, ctx: &Context, offset: u16) -> Response {
+        (**self).on_read(ctx, offset).await
+    }
+
+    async fn on_write(&self, ctx: &Context, data: Vec<u8>, kind: WriteKind) -> Response {
+        (**self).on_write(ctx, data, kind).await
+    }
+
+    async fn on_subscribe(&self, ctx: &Context) {
+        (**self).on_subscribe(ctx).await
+    }
+
+    async fn on_unsubscribe(&self, ctx: &Context) {
+        (**self).on_unsubscribe(ctx).await
+    }
+
+    async fn on_indication_confirmed(&self, ctx: &Context) {
+        (**self).on_indication_confirmed(ctx).await
+    }
+}
+
+// Spawns a task that feeds `handler` the requests sent through the returned sender, one at a
+// time. Use the sender for every property the handler answers, like a sender from
+// `event::channel(capacity)`; the task ends once all of its clones are dropped.
+pub fn spawn<H: CharacteristicHandler>(handler: H, capacity: usize) -> EventSender {
+    let (sender, mut receiver) = event::channel(capacity);
+    tokio::spawn(async move {
+        let mut ctx = Context::default();
+        while let Some(event) = receiver.next().await {
+            match event {
+                Event::ReadRequest(read_request) => {
+                    let response = handler.on_read(&ctx, read_request.offset).await;
+                    let _ = read_request.response.send(response);
+                }
+                Event::WriteRequest(write_request) => {
+                    let kind = if write_request.without_response {
+                        WriteKind::Command
+                    } else if write_request.offset > 0 {
+                        WriteKind::Prepared {
+                            offset: write_request.offset,
+                        }
+                    } else {
+                        WriteKind::Request
+                    };
+                    let response = handler.on_write(&ctx, write_request.data, kind).await;
+                    let _ = write_request.response.send(response);
+                }
+                Event::NotifySubscribe(notify_subscribe) => {
+                    ctx.notifier = Some(notify_subscribe.notification);
+                    handler.on_subscribe(&ctx).await;
+                }
+                Event::NotifyUnsubscribe => {
+                    handler.on_unsubscribe(&ctx).await;
+                    ctx.notifier = None;
+                }
+                Event::IndicationConfirmed => handler.on_indication_confirmed(&ctx).await,
+            }
+        }
+    });
+    sender
+}
+
+// A handler set with `Characteristic::with_handler` or `Descriptor::with_handler`. It answers
+// every property that's declared in place of the property's own sender, so properties can be
+// declared with `Properties::declare`. Its task is spawned with the first request, after the
+// application is registered, so characteristics can be built outside the runtime.
+#[derive(Clone)]
+pub(crate) struct LazyHandler {
+    handler: Arc<dyn CharacteristicHandler>,
+    sender: Arc<Mutex<Option<EventSender>>>,
+}
+
+impl LazyHandler {
+    pub fn new<H: CharacteristicHandler>(handler: H) -> Self {
+        LazyHandler {
+            handler: Arc::new(handler),
+            sender: Arc::default(),
+        }
+    }
+
+    fn sender(&self) -> EventSender {
+        self.sender
+            .lock_or_recover()
+            // The handler takes requests one at a time anyway
+            .get_or_insert_with(|| spawn(Arc::clone(&self.handler), 1))
+            .clone()
+    }
+}
+
+impl fmt::Debug for LazyHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LazyHandler")
+    }
+}
+
+// Where a request to a property with `event_sender` goes
+pub(crate) fn route(handler: &Option<LazyHandler>, event_sender: EventSender) -> EventSender {
+    match handler {
+        Some(handler) => handler.sender(),
+        None => event_sender,
+    }
+}
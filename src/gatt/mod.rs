@@ -11,3 +11,5 @@ pub mod descriptor;
 pub mod service;
 
 pub mod event;
+pub mod handler;
+pub mod notification;
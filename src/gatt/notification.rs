@@ -0,0 +1,324 @@
+use futures::{
+    channel::oneshot,
+    future,
+    prelude::*,
+    task::{Context, Poll, Waker},
+};
+use std::{
+    collections::VecDeque,
+    error, fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use crate::lock::LockExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotificationQueue {
+    pub(crate) capacity: usize,
+    pub(crate) overflow: OverflowPolicy,
+}
+
+impl NotificationQueue {
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        NotificationQueue {
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+}
+
+impl Default for NotificationQueue {
+    fn default() -> Self {
+        NotificationQueue::new(1, OverflowPolicy::Block)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyError {
+    QueueFull,
+    Dropped,
+    Unsubscribed,
+    SendFailed,
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            NotifyError::QueueFull => "notification queue is full",
+            NotifyError::Dropped => "notification was dropped in favour of a newer one",
+            NotifyError::Unsubscribed => "central unsubscribed from notifications",
+            NotifyError::SendFailed => "notification could not be sent",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl error::Error for NotifyError {}
+
+type DeliverySender = oneshot::Sender<Result<(), NotifyError>>;
+type DeliveryReceiver = oneshot::Receiver<Result<(), NotifyError>>;
+
+#[derive(Debug)]
+pub(crate) struct PendingNotification {
+    pub value: Vec<u8>,
+    delivery: DeliverySender,
+}
+
+impl PendingNotification {
+    pub fn complete(self, result: Result<(), NotifyError>) {
+        let _ = self.delivery.send(result);
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    queue: VecDeque<PendingNotification>,
+    closed: bool,
+    notifiers: usize,
+    receiver: Option<Waker>,
+    blocked: Vec<Waker>,
+}
+
+impl State {
+    fn close(&mut self) {
+        self.closed = true;
+        for pending in self.queue.drain(..) {
+            pending.complete(Err(NotifyError::Unsubscribed));
+        }
+        self.wake_blocked();
+        if let Some(waker) = self.receiver.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_blocked(&mut self) {
+        for waker in self.blocked.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    config: NotificationQueue,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    shared: Arc<Shared>,
+}
+
+impl Notifier {
+    // Queues `value` and resolves once the bus has it, or with the reason it was not sent
+    pub async fn notify(&self, value: Vec<u8>) -> Result<(), NotifyError> {
+        let mut value = Some(value);
+        let delivery = future::poll_fn(|cx| self.poll_enqueue(&mut value, cx)).await?;
+        delivery.await.unwrap_or(Err(NotifyError::Unsubscribed))
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.shared.state.lock_or_recover().closed
+    }
+
+    pub(crate) fn close(&self) {
+        self.shared.state.lock_or_recover().close();
+    }
+
+    fn poll_enqueue(
+        &self,
+        value: &mut Option<Vec<u8>>,
+        cx: &mut Context,
+    ) -> Poll<Result<DeliveryReceiver, NotifyError>> {
+        let mut state = self.shared.state.lock_or_recover();
+        if state.closed {
+            return Poll::Ready(Err(NotifyError::Unsubscribed));
+        }
+
+        if state.queue.len() >= self.shared.config.capacity {
+            match self.shared.config.overflow {
+                OverflowPolicy::DropNewest => return Poll::Ready(Err(NotifyError::QueueFull)),
+                OverflowPolicy::DropOldest => {
+                    if let Some(evicted) = state.queue.pop_front() {
+                        evicted.complete(Err(NotifyError::Dropped));
+                    }
+                }
+                OverflowPolicy::Block => {
+                    state.blocked.push(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        let (delivery, receiver) = oneshot::channel();
+        state.queue.push_back(PendingNotification {
+            value: value.take().expect("Notification polled after completion"),
+            delivery,
+        });
+        if let Some(waker) = state.receiver.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(receiver))
+    }
+}
+
+impl Clone for Notifier {
+    fn clone(&self) -> Self {
+        self.shared.state.lock_or_recover().notifiers += 1;
+        Notifier {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock_or_recover();
+        state.notifiers -= 1;
+        if state.notifiers == 0 {
+            if let Some(waker) = state.receiver.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Notifications {
+    shared: Arc<Shared>,
+}
+
+impl Stream for Notifications {
+    type Item = PendingNotification;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.state.lock_or_recover();
+        if let Some(pending) = state.queue.pop_front() {
+            state.wake_blocked();
+            return Poll::Ready(Some(pending));
+        }
+        if state.closed || state.notifiers == 0 {
+            return Poll::Ready(None);
+        }
+        state.receiver = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notifications {
+    fn drop(&mut self) {
+        self.shared.state.lock_or_recover().close();
+    }
+}
+
+pub(crate) fn channel(config: NotificationQueue) -> (Notifier, Notifications) {
+    let shared = Arc::new(Shared {
+        config,
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(config.capacity),
+            closed: false,
+            notifiers: 1,
+            receiver: None,
+            blocked: Vec::new(),
+        }),
+    });
+    (
+        Notifier {
+            shared: Arc::clone(&shared),
+        },
+        Notifications { shared },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, pin_mut, poll};
+
+    #[test]
+    fn test_drop_newest_refuses_when_full() {
+        let (notifier, mut notifications) =
+            channel(NotificationQueue::new(1, OverflowPolicy::DropNewest));
+        block_on(async {
+            let first = notifier.notify(vec![1]);
+            pin_mut!(first);
+            assert!(poll!(&mut first).is_pending());
+            assert_eq!(notifier.notify(vec![2]).await, Err(NotifyError::QueueFull));
+
+            let pending = notifications.next().await.unwrap();
+            assert_eq!(pending.value, vec![1]);
+            pending.complete(Ok(()));
+            assert_eq!(first.await, Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_drop_oldest_evicts_queued() {
+        let (notifier, mut notifications) =
+            channel(NotificationQueue::new(1, OverflowPolicy::DropOldest));
+        block_on(async {
+            let first = notifier.notify(vec![1]);
+            let second = notifier.notify(vec![2]);
+            pin_mut!(first, second);
+            assert!(poll!(&mut first).is_pending());
+            assert!(poll!(&mut second).is_pending());
+            assert_eq!(first.await, Err(NotifyError::Dropped));
+
+            let pending = notifications.next().await.unwrap();
+            assert_eq!(pending.value, vec![2]);
+            pending.complete(Ok(()));
+            assert_eq!(second.await, Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_block_waits_for_room() {
+        let (notifier, mut notifications) =
+            channel(NotificationQueue::new(1, OverflowPolicy::Block));
+        block_on(async {
+            let first = notifier.notify(vec![1]);
+            let second = notifier.notify(vec![2]);
+            pin_mut!(first, second);
+            assert!(poll!(&mut first).is_pending());
+            assert!(poll!(&mut second).is_pending());
+            assert_eq!(notifier.shared.state.lock_or_recover().queue.len(), 1);
+
+            let pending = notifications.next().await.unwrap();
+            assert_eq!(pending.value, vec![1]);
+            pending.complete(Ok(()));
+            assert_eq!(first.await, Ok(()));
+
+            // Taking the first one off made room for the second
+            assert!(poll!(&mut second).is_pending());
+            let pending = notifications.next().await.unwrap();
+            assert_eq!(pending.value, vec![2]);
+            pending.complete(Ok(()));
+            assert_eq!(second.await, Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_unsubscribe_fails_queued_notifications() {
+        let (notifier, notifications) = channel(NotificationQueue::new(2, OverflowPolicy::Block));
+        block_on(async {
+            let first = notifier.notify(vec![1]);
+            pin_mut!(first);
+            assert!(poll!(&mut first).is_pending());
+            drop(notifications);
+            assert_eq!(first.await, Err(NotifyError::Unsubscribed));
+            assert!(!notifier.is_subscribed());
+            assert_eq!(
+                notifier.notify(vec![2]).await,
+                Err(NotifyError::Unsubscribed)
+            );
+        });
+    }
+}
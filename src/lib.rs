@@ -2,9 +2,11 @@
 // warnings caused by `ATOMIC_USIZE_INIT` being deprecated
 #![allow(deprecated)]
 
+pub mod advertisement;
 mod error;
 pub mod gatt;
+mod lock;
 mod peripheral;
 mod uuid;
 
-pub use self::{error::*, peripheral::Peripheral, uuid::*};
+pub use self::{error::*, peripheral::*, uuid::*};
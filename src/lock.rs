@@ -0,0 +1,13 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// A poisoned lock only means some other thread panicked while holding it. The
+// guarded state is still usable, and D-Bus callbacks must not panic over it.
+pub(crate) trait LockExt<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -1,8 +1,8 @@
 use dbus::{
-    arg::{messageitem::MessageItem, RefArg, Variant},
+    arg::{messageitem::MessageItem, Get, RefArg, Variant},
     Path,
 };
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, convert::TryFrom, sync::Arc};
 
 use super::{
     connection::Connection,
@@ -11,7 +11,13 @@ use super::{
         LE_ADVERTISING_MANAGER_IFACE,
     },
 };
-use crate::Error;
+use crate::{
+    advertisement::{
+        capabilities::AdvertisingCapabilities,
+        data::{Include, SecondaryChannel},
+    },
+    Error, ErrorKind, ErrorType,
+};
 
 #[derive(Debug, Clone)]
 pub struct Adapter {
@@ -23,23 +29,45 @@ type ManagedObjectsProps =
     HashMap<Path<'static>, HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>>;
 
 impl Adapter {
-    async fn find_adapter(connection: &Arc<Connection>) -> Result<Path<'static>, Error> {
+    // `selector` is either the adapter's name, e.g. `hci1`, or its object path
+    async fn find_adapter(
+        connection: &Arc<Connection>,
+        selector: Option<&str>,
+    ) -> Result<Path<'static>, Error> {
         let path = "/".into();
         let proxy = connection.get_bluez_proxy(&path);
 
         let (props,): (ManagedObjectsProps,) = proxy
             .method_call(DBUS_OBJECTMANAGER_IFACE, "GetManagedObjects", ())
             .await?;
-        Ok(props
+        props
             .into_iter()
-            .find(|(_path, props)| props.contains_key(LE_ADVERTISING_MANAGER_IFACE))
+            .filter(|(_path, props)| props.contains_key(LE_ADVERTISING_MANAGER_IFACE))
             .map(|(path, _props)| path)
-            .expect("LEAdvertisingManager1 interface not found"))
+            .find(|path| match selector {
+                Some(selector) if selector.starts_with('/') => &**path == selector,
+                Some(selector) => path.rsplit('/').next() == Some(selector),
+                None => true,
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::AdapterNotFound,
+                    match selector {
+                        Some(selector) => format!(
+                            "No adapter {} with the LEAdvertisingManager1 interface was found",
+                            selector
+                        ),
+                        None => "No adapter with the LEAdvertisingManager1 interface was found"
+                            .to_owned(),
+                    },
+                    ErrorType::Bluez,
+                )
+            })
     }
 
     #[allow(clippy::new_ret_no_self)]
-    pub async fn new(connection: Arc<Connection>) -> Result<Self, Error> {
-        Adapter::find_adapter(&connection)
+    pub async fn new(connection: Arc<Connection>, selector: Option<&str>) -> Result<Self, Error> {
+        Adapter::find_adapter(&connection, selector)
             .await
             .map(|object_path| Adapter {
                 object_path,
@@ -70,4 +98,100 @@ impl Adapter {
             .await?;
         Ok(powered.0)
     }
+
+    // SupportedInstances only counts the slots still free, so the active ones are added back
+    pub async fn total_advertisement_instances(&self) -> Result<u16, Error> {
+        let supported: u8 = self
+            .advertising_manager_property("SupportedInstances")
+            .await?;
+        let active: u8 = self.advertising_manager_property("ActiveInstances").await?;
+        Ok(u16::from(supported) + u16::from(active))
+    }
+
+    pub async fn advertising_capabilities(&self) -> Result<AdvertisingCapabilities, Error> {
+        let proxy = self.connection.get_bluez_proxy(&self.object_path);
+        let (props,): (HashMap<String, Variant<Box<dyn RefArg>>>,) = proxy
+            .method_call(
+                DBUS_PROPERTIES_IFACE,
+                "GetAll",
+                (LE_ADVERTISING_MANAGER_IFACE,),
+            )
+            .await?;
+
+        let number = |name: &str| props.get(name).and_then(|value| value.0.as_u64());
+        let strings = |name: &str| {
+            props
+                .get(name)
+                .and_then(|value| value.0.as_iter())
+                .map(|values| {
+                    values
+                        .filter_map(|value| value.as_str().map(ToOwned::to_owned))
+                        .collect::<Vec<String>>()
+                })
+                .unwrap_or_default()
+        };
+
+        // SupportedCapabilities is a dictionary, which iterates as alternating keys and values
+        let mut capabilities = HashMap::new();
+        if let Some(mut iter) = props
+            .get("SupportedCapabilities")
+            .and_then(|value| value.0.as_iter())
+        {
+            while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+                if let (Some(key), Some(value)) = (key.as_str(), value.as_i64()) {
+                    capabilities.insert(key.to_owned(), value);
+                }
+            }
+        }
+        let capability = |name: &str| capabilities.get(name).cloned();
+
+        Ok(AdvertisingCapabilities {
+            supported_instances: number("SupportedInstances")
+                .and_then(|value| u8::try_from(value).ok())
+                .unwrap_or(0),
+            active_instances: number("ActiveInstances")
+                .and_then(|value| u8::try_from(value).ok())
+                .unwrap_or(0),
+            supported_includes: strings("SupportedIncludes")
+                .iter()
+                .filter_map(|include| match include.as_str() {
+                    "tx-power" => Some(Include::TxPower),
+                    "appearance" => Some(Include::Appearance),
+                    "local-name" => Some(Include::LocalName),
+                    _ => None,
+                })
+                .collect(),
+            supported_secondary_channels: strings("SupportedSecondaryChannels")
+                .iter()
+                .filter_map(|channel| match channel.as_str() {
+                    "1M" => Some(SecondaryChannel::OneM),
+                    "2M" => Some(SecondaryChannel::TwoM),
+                    "Coded" => Some(SecondaryChannel::Coded),
+                    _ => None,
+                })
+                .collect(),
+            supported_features: strings("SupportedFeatures"),
+            max_advertising_length: capability("MaxAdvLen")
+                .and_then(|value| u8::try_from(value).ok()),
+            max_scan_response_length: capability("MaxScnRspLen")
+                .and_then(|value| u8::try_from(value).ok()),
+            min_tx_power: capability("MinTxPower").and_then(|value| i16::try_from(value).ok()),
+            max_tx_power: capability("MaxTxPower").and_then(|value| i16::try_from(value).ok()),
+        })
+    }
+
+    async fn advertising_manager_property<T>(&self, name: &str) -> Result<T, Error>
+    where
+        T: for<'a> Get<'a> + 'static,
+    {
+        let proxy = self.connection.get_bluez_proxy(&self.object_path);
+        let (value,): (Variant<T>,) = proxy
+            .method_call(
+                DBUS_PROPERTIES_IFACE,
+                "Get",
+                (LE_ADVERTISING_MANAGER_IFACE, name),
+            )
+            .await?;
+        Ok(value.0)
+    }
 }
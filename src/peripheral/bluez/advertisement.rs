@@ -1,111 +1,613 @@
 use dbus::{
-    arg::{RefArg, Variant},
-    channel::MatchingReceiver,
-    message::MatchRule,
-    Path,
+    arg::{Append, Arg, RefArg, Variant},
+    channel::Sender,
+    nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
+    Message, Path,
 };
+use dbus_crossroads::{IfaceBuilder, MethodErr};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
     },
+    time::Duration,
 };
+use uuid::Uuid;
 
 use super::{
+    adapter::Adapter,
     common,
-    connection::Connection,
-    constants::{LE_ADVERTISEMENT_IFACE, LE_ADVERTISING_MANAGER_IFACE, PATH_BASE},
+    connection::{Connection, Route},
+    constants::{DBUS_PROPERTIES_IFACE, LE_ADVERTISEMENT_IFACE, LE_ADVERTISING_MANAGER_IFACE},
 };
-use crate::Error;
+use crate::{
+    advertisement::{
+        ad_structure::{
+            self, AdStructure, FLAGS_BR_EDR_NOT_SUPPORTED, FLAGS_LE_GENERAL_DISCOVERABLE,
+        },
+        data::{AdvertisementData, Include, SecondaryChannel},
+        eddystone::EddystoneTlm,
+    },
+    lock::LockExt,
+    peripheral::event::{EventDispatcher, PeripheralEvent},
+    Error, ErrorKind, ErrorType,
+};
+
+pub type Instances = Arc<Mutex<BTreeSet<u16>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdvertisementType {
+    Peripheral,
+    Broadcast,
+}
+
+impl AdvertisementType {
+    fn as_str(self) -> &'static str {
+        match self {
+            AdvertisementType::Peripheral => "peripheral",
+            AdvertisementType::Broadcast => "broadcast",
+        }
+    }
+}
+
+type PropertyGetter = Box<dyn Fn(&AdvertisementData) -> Option<Box<dyn RefArg>> + Send + Sync>;
+
+// Getters of every property backed by `AdvertisementData`, used to announce changes
+#[derive(Default)]
+struct DataProperties(Vec<(&'static str, PropertyGetter)>);
+
+impl fmt::Debug for DataProperties {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+// Releases the instance and stops routing its method calls once the last
+// handle to the advertisement is gone.
+#[derive(Debug)]
+struct Registration {
+    instance: u16,
+    instances: Instances,
+    connection: Arc<Connection>,
+    adapter: Path<'static>,
+    object_path: Path<'static>,
+    is_advertising: Arc<AtomicBool>,
+    _route: Route,
+}
+
+impl Registration {
+    // Unregisters without waiting for BlueZ, if the advertisement is running
+    fn release(&self) {
+        if self.is_advertising.swap(false, Ordering::Relaxed) {
+            self.connection.call_bluez_no_reply(
+                &self.adapter,
+                LE_ADVERTISING_MANAGER_IFACE,
+                "UnregisterAdvertisement",
+                (&self.object_path,),
+            );
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.release();
+        self.instances.lock_or_recover().remove(&self.instance);
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Advertisement {
     connection: Arc<Connection>,
-    adapter: Path<'static>,
-    pub object_path: Path<'static>,
-    tree: Arc<Mutex<common::Tree>>,
+    adapter: Adapter,
+    object_path: Path<'static>,
+    registration: Arc<Registration>,
     is_advertising: Arc<AtomicBool>,
-    name: Arc<Mutex<Option<String>>>,
-    uuids: Arc<Mutex<Option<Vec<String>>>>,
+    advertisement_type: Arc<Mutex<AdvertisementType>>,
+    data: Arc<Mutex<AdvertisementData>>,
+    // Bumped on every start and stop so background refreshes know when to quit
+    generation: Arc<AtomicUsize>,
+    properties: Arc<DataProperties>,
+}
+
+// Handle that doesn't keep the advertisement instance allocated
+#[derive(Debug, Clone)]
+pub(crate) struct WeakAdvertisement {
+    connection: Arc<Connection>,
+    adapter: Adapter,
+    object_path: Path<'static>,
+    registration: Weak<Registration>,
+    is_advertising: Arc<AtomicBool>,
+    advertisement_type: Arc<Mutex<AdvertisementType>>,
+    data: Arc<Mutex<AdvertisementData>>,
+    generation: Arc<AtomicUsize>,
+    properties: Arc<DataProperties>,
+}
+
+impl WeakAdvertisement {
+    pub fn upgrade(&self) -> Option<Advertisement> {
+        let registration = self.registration.upgrade()?;
+        Some(Advertisement {
+            connection: self.connection.clone(),
+            adapter: self.adapter.clone(),
+            object_path: self.object_path.clone(),
+            registration,
+            is_advertising: self.is_advertising.clone(),
+            advertisement_type: self.advertisement_type.clone(),
+            data: self.data.clone(),
+            generation: self.generation.clone(),
+            properties: self.properties.clone(),
+        })
+    }
 }
 
 impl Advertisement {
-    pub fn new(connection: Arc<Connection>, adapter: Path<'static>) -> Self {
+    pub(crate) fn new(
+        connection: Arc<Connection>,
+        adapter: Adapter,
+        namespace: &Path,
+        instances: &Instances,
+        total_instances: u16,
+        events: EventDispatcher,
+    ) -> Result<Self, Error> {
+        let instance = {
+            let mut instances = instances.lock_or_recover();
+            let instance = (0..total_instances)
+                .find(|instance| !instances.contains(instance))
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::ResourcesExhausted,
+                        format!(
+                            "All {} advertisement instances of the adapter are in use",
+                            total_instances
+                        ),
+                        ErrorType::Bluez,
+                    )
+                })?;
+            instances.insert(instance);
+            instance
+        };
+
         let mut tree = common::Tree::new();
         let is_advertising = Arc::new(AtomicBool::new(false));
         let is_advertising_release = is_advertising.clone();
 
-        let name = Arc::new(Mutex::new(None));
-        let name_property = name.clone();
-
-        let uuids = Arc::new(Mutex::new(None));
-        let uuids_property = uuids.clone();
+        let advertisement_type = Arc::new(Mutex::new(AdvertisementType::Peripheral));
+        let advertisement_type_property = advertisement_type.clone();
+        let data = Arc::new(Mutex::new(AdvertisementData::default()));
 
-        let object_path: Path = format!("{}/advertisement{:04}", PATH_BASE, 0).into();
+        let object_path: Path = format!("{}/advertisement{:04}", namespace, instance).into();
 
+        let mut properties = DataProperties::default();
         let iface_token = tree.register(LE_ADVERTISEMENT_IFACE, |b| {
-            b.method_with_cr_async("Release", (), (), move |mut ctx, _cr, ()| {
+            b.method("Release", (), (), move |_ctx, _cr, ()| {
                 is_advertising_release.store(false, Ordering::Relaxed);
-                futures::future::ready(ctx.reply(Ok(())))
-            });
-            b.property("Type")
-                .get(|_ctx, _cr| Ok("peripheral".to_owned()));
-            b.property("LocalName").get(move |_ctx, _cr| {
-                Ok(name_property
-                    .lock()
-                    .expect("Poisoned mutex")
-                    .clone()
-                    .unwrap_or_else(String::new))
-            });
-            b.property("ServiceUUIDs").get(move |_ctx, _cr| {
-                Ok(uuids_property
-                    .lock()
-                    .expect("Poisoned mutex")
-                    .clone()
-                    .unwrap_or_else(Vec::new))
+                events.emit(PeripheralEvent::AdvertisementReleased { instance });
+                Ok(())
+            });
+            b.property("Type").get(move |_ctx, _cr| {
+                Ok(advertisement_type_property
+                    .lock_or_recover()
+                    .as_str()
+                    .to_owned())
+            });
+            data_property(b, &mut properties, "LocalName", &data, |data| {
+                data.local_name.clone()
+            });
+            data_property(b, &mut properties, "ServiceUUIDs", &data, |data| {
+                Some(uuid_strings(&data.service_uuids))
+            });
+            data_property(b, &mut properties, "SolicitUUIDs", &data, |data| {
+                Some(uuid_strings(&data.solicit_uuids))
+            });
+            data_property(b, &mut properties, "ManufacturerData", &data, |data| {
+                Some(manufacturer_data_map(&data.manufacturer_data))
+            });
+            data_property(b, &mut properties, "ServiceData", &data, |data| {
+                Some(service_data_map(&data.service_data))
+            });
+            data_property(b, &mut properties, "Appearance", &data, |data| {
+                data.appearance
+            });
+            data_property(b, &mut properties, "TxPower", &data, |data| data.tx_power);
+            data_property(b, &mut properties, "Includes", &data, |data| {
+                Some(
+                    data.includes
+                        .iter()
+                        .map(|include| include_name(*include).to_owned())
+                        .collect::<Vec<String>>(),
+                )
+            });
+            data_property(b, &mut properties, "Discoverable", &data, |data| {
+                data.discoverable
+            });
+            data_property(b, &mut properties, "DiscoverableTimeout", &data, |data| {
+                data.discoverable_timeout.map(seconds)
+            });
+            data_property(b, &mut properties, "Timeout", &data, |data| {
+                data.timeout.map(seconds)
+            });
+            data_property(b, &mut properties, "Duration", &data, |data| {
+                data.duration.map(seconds)
+            });
+            data_property(b, &mut properties, "MinInterval", &data, |data| {
+                data.min_interval.map(milliseconds)
+            });
+            data_property(b, &mut properties, "MaxInterval", &data, |data| {
+                data.max_interval.map(milliseconds)
+            });
+            data_property(b, &mut properties, "SecondaryChannel", &data, |data| {
+                data.secondary_channel
+                    .map(|secondary_channel| secondary_channel_name(secondary_channel).to_owned())
+            });
+            data_property(
+                b,
+                &mut properties,
+                "ScanResponseServiceUUIDs",
+                &data,
+                |data| {
+                    data.scan_response
+                        .as_ref()
+                        .map(|scan_response| uuid_strings(&scan_response.service_uuids))
+                },
+            );
+            data_property(
+                b,
+                &mut properties,
+                "ScanResponseSolicitUUIDs",
+                &data,
+                |data| {
+                    data.scan_response
+                        .as_ref()
+                        .map(|scan_response| uuid_strings(&scan_response.solicit_uuids))
+                },
+            );
+            data_property(
+                b,
+                &mut properties,
+                "ScanResponseManufacturerData",
+                &data,
+                |data| {
+                    data.scan_response.as_ref().map(|scan_response| {
+                        manufacturer_data_map(&scan_response.manufacturer_data)
+                    })
+                },
+            );
+            data_property(
+                b,
+                &mut properties,
+                "ScanResponseServiceData",
+                &data,
+                |data| {
+                    data.scan_response
+                        .as_ref()
+                        .map(|scan_response| service_data_map(&scan_response.service_data))
+                },
+            );
+            // There is no dedicated property for the name, so it goes in as a raw AD structure
+            data_property(b, &mut properties, "ScanResponseData", &data, |data| {
+                let local_name = data.scan_response.as_ref()?.local_name.as_ref()?;
+                let ad_type = AdStructure::CompleteLocalName(local_name.clone()).ad_type();
+                let mut ad_data = HashMap::new();
+                ad_data.insert(ad_type, Variant(local_name.as_bytes().to_vec()));
+                Some(ad_data)
             });
         });
         let ifaces = [iface_token, tree.object_manager()];
         tree.insert(object_path.clone(), &ifaces, ());
 
-        let tree = Arc::new(Mutex::new(tree));
-
-        {
-            let tree = tree.clone();
-            let mut match_rule = MatchRule::new_method_call();
-            match_rule.path = Some(object_path.clone());
-            connection.default.start_receive(
-                match_rule,
-                Box::new(move |msg, conn| {
-                    tree.lock().unwrap().handle_message(msg, conn).unwrap();
-                    true
-                }),
-            );
-        }
+        let registration = Arc::new(Registration {
+            instance,
+            instances: instances.clone(),
+            connection: connection.clone(),
+            adapter: adapter.object_path.clone(),
+            object_path: object_path.clone(),
+            is_advertising: is_advertising.clone(),
+            _route: connection.route(object_path.clone(), false, Arc::new(Mutex::new(tree))),
+        });
 
-        Advertisement {
+        Ok(Advertisement {
             connection,
             adapter,
             object_path,
-            tree,
+            registration,
             is_advertising,
-            name,
-            uuids,
+            advertisement_type,
+            data,
+            generation: Arc::new(AtomicUsize::new(0)),
+            properties: Arc::new(properties),
+        })
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakAdvertisement {
+        WeakAdvertisement {
+            connection: self.connection.clone(),
+            adapter: self.adapter.clone(),
+            object_path: self.object_path.clone(),
+            registration: Arc::downgrade(&self.registration),
+            is_advertising: self.is_advertising.clone(),
+            advertisement_type: self.advertisement_type.clone(),
+            data: self.data.clone(),
+            generation: self.generation.clone(),
+            properties: self.properties.clone(),
+        }
+    }
+
+    // Registers the advertisement again if it was running when BlueZ lost track of it
+    pub(crate) async fn restore(&self) -> Result<(), Error> {
+        if !self.is_advertising() {
+            return Ok(());
         }
+        self.register().await
+    }
+
+    // Best effort stop for when there is no way to wait for BlueZ
+    pub(crate) fn release(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.registration.release();
+    }
+
+    pub fn instance(&self) -> u16 {
+        self.registration.instance
     }
 
-    pub fn add_name<T: Into<String>>(self: &Self, name: T) {
-        self.name.lock().unwrap().replace(name.into());
+    pub async fn start(&self, advertisement_data: &AdvertisementData) -> Result<(), Error> {
+        self.start_as(AdvertisementType::Peripheral, advertisement_data)
+            .await
     }
 
-    pub fn add_uuids<T: Into<Vec<String>>>(self: &Self, uuids: T) {
-        self.uuids.lock().unwrap().replace(uuids.into());
+    // Broadcast advertisements are non-connectable, e.g. for beacons
+    pub async fn start_broadcast(
+        &self,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        self.start_as(AdvertisementType::Broadcast, advertisement_data)
+            .await
     }
 
-    pub async fn register(self: &Self) -> Result<(), Error> {
+    // Broadcasts Eddystone TLM frames, asking `telemetry` for fresh counters every `interval`
+    pub async fn start_eddystone_tlm<F>(
+        &self,
+        interval: Duration,
+        mut telemetry: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut() -> EddystoneTlm + Send + 'static,
+    {
+        self.start_broadcast(&telemetry().advertisement_data())
+            .await?;
+        self.spawn_refresh(interval, move || telemetry().advertisement_data());
+        Ok(())
+    }
+
+    // Advertises each payload in turn for `interval` before moving on to the next one. All of
+    // them share one advertisement type, connectable or broadcast, as it's only set on start.
+    pub async fn start_rotation(
+        &self,
+        payloads: Vec<AdvertisementData>,
+        interval: Duration,
+        connectable: bool,
+    ) -> Result<(), Error> {
+        if payloads.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidDefinition,
+                "An advertisement rotation needs at least one payload".to_owned(),
+                ErrorType::Bluez,
+            ));
+        }
+        let advertisement_type = if connectable {
+            AdvertisementType::Peripheral
+        } else {
+            AdvertisementType::Broadcast
+        };
+        // Catch oversized payloads now rather than when their turn comes
+        for advertisement_data in payloads.iter() {
+            self.validate(advertisement_type, advertisement_data)
+                .await?;
+        }
+
+        self.start_as(advertisement_type, &payloads[0]).await?;
+        if payloads.len() > 1 {
+            let mut payloads = payloads.into_iter().cycle().skip(1);
+            self.spawn_refresh(interval, move || payloads.next().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    pub async fn stop(&self) -> Result<(), Error> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.unregister().await
+    }
+
+    async fn start_as(
+        &self,
+        advertisement_type: AdvertisementType,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        self.validate(advertisement_type, advertisement_data)
+            .await?;
+
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        // The type is only read on registration, and BlueZ refuses a second one
+        if self.is_advertising() {
+            self.unregister().await?;
+        }
+        *self.advertisement_type.lock_or_recover() = advertisement_type;
+        *self.data.lock_or_recover() = advertisement_data.clone();
+        self.register().await
+    }
+
+    // Replaces the advertised data, stopping any rotation or telemetry updates in progress
+    pub async fn update(&self, advertisement_data: &AdvertisementData) -> Result<(), Error> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.refresh(advertisement_data).await
+    }
+
+    async fn refresh(&self, advertisement_data: &AdvertisementData) -> Result<(), Error> {
+        if !self.is_advertising() {
+            *self.data.lock_or_recover() = advertisement_data.clone();
+            return Ok(());
+        }
+
+        let advertisement_type = *self.advertisement_type.lock_or_recover();
+        self.validate(advertisement_type, advertisement_data)
+            .await?;
+
+        let previous = self.data.lock_or_recover().clone();
+        if needs_registration(&previous, advertisement_data) {
+            self.unregister().await?;
+            *self.data.lock_or_recover() = advertisement_data.clone();
+            return self.register().await;
+        }
+
+        let signal = {
+            let mut data = self.data.lock_or_recover();
+            *data = advertisement_data.clone();
+            self.properties_changed_signal(&previous, &data)
+        };
+        self.connection.send(signal).map_err(|_| {
+            Error::new(
+                ErrorKind::BackendDisconnected,
+                "Could not send PropertiesChanged for the advertisement".to_owned(),
+                ErrorType::Bluez,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn properties_changed_signal(
+        &self,
+        previous: &AdvertisementData,
+        data: &AdvertisementData,
+    ) -> Message {
+        let mut changed_properties = HashMap::new();
+        let mut invalidated_properties = vec![];
+        for (name, getter) in self.properties.0.iter() {
+            match getter(data) {
+                Some(value) => {
+                    changed_properties.insert((*name).to_owned(), Variant(value));
+                }
+                None if getter(previous).is_some() => {
+                    invalidated_properties.push((*name).to_owned())
+                }
+                None => {}
+            }
+        }
+        let signal = PropertiesPropertiesChanged {
+            interface_name: LE_ADVERTISEMENT_IFACE.to_string(),
+            changed_properties,
+            invalidated_properties,
+        };
+        let mut signal_message = Message::signal(
+            &self.object_path,
+            &DBUS_PROPERTIES_IFACE.into(),
+            &"PropertiesChanged".into(),
+        );
+        signal_message.append_all(signal);
+        signal_message
+    }
+
+    async fn validate(
+        &self,
+        advertisement_type: AdvertisementType,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        let max_length = match advertisement_data.secondary_channel {
+            Some(secondary_channel) => {
+                self.check_extended_support(secondary_channel).await?;
+                ad_structure::EXTENDED_MAX_LENGTH
+            }
+            None => ad_structure::LEGACY_MAX_LENGTH,
+        };
+
+        let mut structures = advertisement_data.ad_structures();
+        // bluetoothd prepends the flags structure to connectable advertisements
+        if let AdvertisementType::Peripheral = advertisement_type {
+            structures.push(AdStructure::Flags(
+                FLAGS_LE_GENERAL_DISCOVERABLE | FLAGS_BR_EDR_NOT_SUPPORTED,
+            ));
+        }
+        ad_structure::validate(&structures, max_length)?;
+        if let Some(ref scan_response) = advertisement_data.scan_response {
+            ad_structure::validate(&scan_response.ad_structures(), max_length)?;
+        }
+        Ok(())
+    }
+
+    // Goes by the same capabilities users can query, so the two can't disagree. Failing to read
+    // them is passed on rather than taken for a lack of support.
+    async fn check_extended_support(
+        &self,
+        secondary_channel: SecondaryChannel,
+    ) -> Result<(), Error> {
+        let capabilities = self.adapter.advertising_capabilities().await?;
+        if !capabilities.supports_extended_advertising() {
+            return Err(Error::new(
+                ErrorKind::NotSupported,
+                "The adapter does not support extended advertising".to_owned(),
+                ErrorType::Bluez,
+            ));
+        }
+        let channels = &capabilities.supported_secondary_channels;
+        if !channels.contains(&secondary_channel) {
+            return Err(Error::new(
+                ErrorKind::NotSupported,
+                format!(
+                    "The adapter cannot advertise on the {} secondary channel, it supports: {}",
+                    secondary_channel_name(secondary_channel),
+                    channels
+                        .iter()
+                        .map(|channel| secondary_channel_name(*channel))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                ErrorType::Bluez,
+            ));
+        }
+        Ok(())
+    }
+
+    // Re-registers with the data returned by `next` every `interval`, until the
+    // advertisement is stopped, started again or dropped
+    fn spawn_refresh<F>(&self, interval: Duration, mut next: F)
+    where
+        F: FnMut() -> AdvertisementData + Send + 'static,
+    {
+        let generation = self.generation.load(Ordering::SeqCst);
+        // Holding on to the advertisement would keep its instance from being released
+        let weak = self.downgrade();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(interval).await;
+                let advertisement = match weak.upgrade() {
+                    Some(advertisement) => advertisement,
+                    None => break,
+                };
+                if advertisement.generation.load(Ordering::SeqCst) != generation
+                    || !advertisement.is_advertising()
+                {
+                    break;
+                }
+                if let Err(err) = advertisement.refresh(&next()).await {
+                    log::warn!(
+                        "Could not update advertisement {}: {}",
+                        advertisement.instance(),
+                        err
+                    );
+                    break;
+                }
+            }
+        });
+    }
+
+    async fn register(&self) -> Result<(), Error> {
         // Register with DBus
-        let proxy = self.connection.get_bluez_proxy(&self.adapter);
+        let proxy = self.connection.get_bluez_proxy(&self.adapter.object_path);
         proxy
             .method_call(
                 LE_ADVERTISING_MANAGER_IFACE,
@@ -120,8 +622,8 @@ impl Advertisement {
         Ok(())
     }
 
-    pub async fn unregister(self: &Self) -> Result<(), Error> {
-        let proxy = self.connection.get_bluez_proxy(&self.adapter);
+    async fn unregister(&self) -> Result<(), Error> {
+        let proxy = self.connection.get_bluez_proxy(&self.adapter.object_path);
 
         let method_call = proxy.method_call(
             LE_ADVERTISING_MANAGER_IFACE,
@@ -135,8 +637,87 @@ impl Advertisement {
         Ok(())
     }
 
-    pub fn is_advertising(self: &Self) -> bool {
+    pub fn is_advertising(&self) -> bool {
         let is_advertising = self.is_advertising.clone();
         is_advertising.load(Ordering::Relaxed)
     }
 }
+
+fn manufacturer_data_map(
+    manufacturer_data: &BTreeMap<u16, Vec<u8>>,
+) -> HashMap<u16, Variant<Vec<u8>>> {
+    manufacturer_data
+        .iter()
+        .map(|(company_id, bytes)| (*company_id, Variant(bytes.clone())))
+        .collect()
+}
+
+fn service_data_map(service_data: &BTreeMap<Uuid, Vec<u8>>) -> HashMap<String, Variant<Vec<u8>>> {
+    service_data
+        .iter()
+        .map(|(uuid, bytes)| (uuid.to_string(), Variant(bytes.clone())))
+        .collect()
+}
+
+// Optional properties are left out of `GetAll` when their getter fails, which
+// is how BlueZ is told that a field isn't set.
+fn data_property<A, F>(
+    b: &mut IfaceBuilder<()>,
+    properties: &mut DataProperties,
+    name: &'static str,
+    data: &Arc<Mutex<AdvertisementData>>,
+    getter: F,
+) where
+    A: Arg + RefArg + Append + Send + 'static,
+    F: Fn(&AdvertisementData) -> Option<A> + Send + Sync + 'static,
+{
+    let getter = Arc::new(getter);
+    let property_getter = getter.clone();
+    properties.0.push((
+        name,
+        Box::new(move |data| property_getter(data).map(|value| Box::new(value) as _)),
+    ));
+
+    let data = data.clone();
+    b.property(name).get(move |_ctx, _cr| {
+        getter(&data.lock_or_recover()).ok_or_else(|| MethodErr::no_property(&name))
+    });
+}
+
+fn uuid_strings(uuids: &[Uuid]) -> Vec<String> {
+    uuids.iter().map(ToString::to_string).collect()
+}
+
+fn include_name(include: Include) -> &'static str {
+    match include {
+        Include::TxPower => "tx-power",
+        Include::Appearance => "appearance",
+        Include::LocalName => "local-name",
+    }
+}
+
+// bluetoothd refreshes the payload when content properties change, but
+// parameters of the advertising set are only read on registration
+fn needs_registration(previous: &AdvertisementData, data: &AdvertisementData) -> bool {
+    previous.secondary_channel != data.secondary_channel
+        || previous.min_interval != data.min_interval
+        || previous.max_interval != data.max_interval
+        || previous.duration != data.duration
+        || previous.timeout != data.timeout
+}
+
+fn secondary_channel_name(secondary_channel: SecondaryChannel) -> &'static str {
+    match secondary_channel {
+        SecondaryChannel::OneM => "1M",
+        SecondaryChannel::TwoM => "2M",
+        SecondaryChannel::Coded => "Coded",
+    }
+}
+
+fn seconds(duration: Duration) -> u16 {
+    u16::try_from(duration.as_secs()).unwrap_or(u16::MAX)
+}
+
+fn milliseconds(duration: Duration) -> u32 {
+    u32::try_from(duration.as_millis()).unwrap_or(u32::MAX)
+}
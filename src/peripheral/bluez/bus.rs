@@ -0,0 +1,208 @@
+use dbus::{
+    channel::Channel,
+    nonblock::{NonblockReply, Process, SyncConnection},
+};
+use futures::prelude::*;
+use std::{
+    error,
+    os::unix::io::RawFd,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::io::Registration;
+
+use super::constants::BLUEZ_SERVICE_NAME;
+use crate::Error;
+
+// Drives a connection's I/O, and resolves with the reason once the connection is gone
+pub type Driver = Pin<Box<dyn Future<Output = Box<dyn error::Error + Send + Sync>> + Send>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusAddress {
+    System,
+    Session,
+    // Any D-Bus server address, e.g. `unix:path=/tmp/test-bus`
+    Address(String),
+}
+
+// Where to find BlueZ: the system bus and `org.bluez` unless pointed elsewhere, e.g. at a
+// `FakeBluez` on a private bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusConfig {
+    pub address: BusAddress,
+    pub service_name: String,
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        BusConfig::system()
+    }
+}
+
+impl BusConfig {
+    pub fn system() -> Self {
+        BusConfig {
+            address: BusAddress::System,
+            service_name: BLUEZ_SERVICE_NAME.to_owned(),
+        }
+    }
+
+    pub fn session() -> Self {
+        BusConfig {
+            address: BusAddress::Session,
+            ..BusConfig::system()
+        }
+    }
+
+    pub fn address<T: Into<String>>(address: T) -> Self {
+        BusConfig {
+            address: BusAddress::Address(address.into()),
+            ..BusConfig::system()
+        }
+    }
+
+    pub fn with_service_name<T: Into<String>>(self, service_name: T) -> Self {
+        BusConfig {
+            service_name: service_name.into(),
+            ..self
+        }
+    }
+}
+
+pub fn connect(address: &BusAddress) -> Result<(Driver, Arc<SyncConnection>), Error> {
+    match address {
+        BusAddress::System => {
+            let (resource, connection) = dbus_tokio::connection::new_system_sync()?;
+            Ok((Box::pin(resource), connection))
+        }
+        BusAddress::Session => {
+            let (resource, connection) = dbus_tokio::connection::new_session_sync()?;
+            Ok((Box::pin(resource), connection))
+        }
+        BusAddress::Address(address) => {
+            let mut channel = Channel::open_private(address)?;
+            channel.register()?;
+            let (resource, connection) = AddressResource::new(channel);
+            Ok((Box::pin(resource), connection))
+        }
+    }
+}
+
+// `dbus_tokio` can only drive connections to the system and session buses, its `IOResource`
+// can't be built for any other channel, so connections opened by address get a copy of it here.
+// That's also why this depends on `mio` and `libc` directly: they're the ones `dbus_tokio` uses.
+struct AddressResource {
+    connection: Arc<SyncConnection>,
+    watch_fd: RawFd,
+    waker: mio::Registration,
+    registrations: Option<(Registration, Registration)>,
+    write_pending: bool,
+}
+
+impl AddressResource {
+    fn new(mut channel: Channel) -> (Self, Arc<SyncConnection>) {
+        channel.set_watch_enabled(true);
+        let watch_fd = channel.watch().fd;
+
+        let mut connection = SyncConnection::from(channel);
+        connection.set_timeout_maker(Some(make_timeout));
+        // Messages sent from other tasks wake the driver up to flush them
+        let (waker, set_readiness) = mio::Registration::new2();
+        connection.set_waker(Some(Box::new(move || {
+            set_readiness
+                .set_readiness(mio::Ready::readable())
+                .map_err(|_| ())
+        })));
+
+        let connection = Arc::new(connection);
+        let resource = AddressResource {
+            connection: connection.clone(),
+            watch_fd,
+            waker,
+            registrations: None,
+            write_pending: false,
+        };
+        (resource, connection)
+    }
+
+    fn poll_io(&mut self, cx: &mut Context) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        // Registering needs the reactor, which is only known to be running once polled
+        if self.registrations.is_none() {
+            let watch = Registration::new(&mio::unix::EventedFd(&self.watch_fd))?;
+            let waker = Registration::new(&self.waker)?;
+            self.registrations = Some((watch, waker));
+        }
+        let (watch, waker) = match &self.registrations {
+            Some(registrations) => registrations,
+            None => return Ok(()),
+        };
+
+        let read_ready = is_ready(watch.poll_read_ready(cx)?);
+        let send_ready = is_ready(waker.poll_read_ready(cx)?);
+        let write_ready = watch
+            .take_write_ready()?
+            .map(|ready| ready.is_writable())
+            .unwrap_or(false);
+        if !(read_ready || send_ready || (self.write_pending && write_ready)) {
+            return Ok(());
+        }
+
+        let channel: &Channel = (*self.connection).as_ref();
+        loop {
+            self.write_pending = false;
+            channel
+                .read_write(Some(Default::default()))
+                .map_err(|_| dbus::Error::new_failed("Read/write failed"))?;
+            self.connection.process_all();
+
+            if channel.has_messages_to_send() {
+                self.write_pending = true;
+                if is_ready(watch.poll_write_ready(cx)?) {
+                    continue;
+                }
+            }
+
+            // libdbus might not have read everything that arrived, and the reactor won't
+            // wake us up for data that was already there
+            let mut byte = 0u8;
+            // SAFETY: `watch_fd` is the channel's socket, kept open by the connection this holds
+            // on to, and `recv` writes at most the one byte it's given. `MSG_PEEK` leaves the data
+            // for libdbus and `MSG_DONTWAIT` keeps it from blocking.
+            let peeked = unsafe {
+                libc::recv(
+                    self.watch_fd,
+                    &mut byte as *mut _ as *mut libc::c_void,
+                    1,
+                    libc::MSG_DONTWAIT | libc::MSG_PEEK,
+                )
+            };
+            if peeked != 1 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Future for AddressResource {
+    type Output = Box<dyn error::Error + Send + Sync>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.poll_io(cx) {
+            Ok(()) => Poll::Pending,
+            Err(err) => Poll::Ready(err),
+        }
+    }
+}
+
+fn is_ready(poll: Poll<mio::Ready>) -> bool {
+    match poll {
+        Poll::Ready(ready) => !ready.is_empty(),
+        Poll::Pending => false,
+    }
+}
+
+fn make_timeout(timeout: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static>> {
+    Box::pin(tokio::time::delay_until(timeout.into()))
+}
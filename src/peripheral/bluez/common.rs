@@ -1,3 +1,4 @@
+use dbus::{tree::MethodErr, Path};
 use dbus_crossroads::Crossroads;
 use std::sync::Arc;
 
@@ -12,26 +13,38 @@ pub enum GattDataType {
 }
 
 impl GattDataType {
-    // Not needed...
-    // pub fn get_service(self: &Self) -> Arc<gatt::service::Service> {
-    //     if let GattDataType::Service(ref service) = self {
-    //         return service.clone();
-    //     }
-    //     panic!("GattDataType is not a Service!");
-    // }
-
-    pub fn get_characteristic(self: &Self) -> Arc<gatt::characteristic::Characteristic> {
-        if let GattDataType::Characteristic(ref characteristic) = self {
-            return characteristic.clone();
+    pub fn get_characteristic(self: &Self) -> Option<Arc<gatt::characteristic::Characteristic>> {
+        match self {
+            GattDataType::Characteristic(characteristic) => Some(characteristic.clone()),
+            _ => None,
         }
-        panic!("GattDataType is not a Characteristic!");
     }
 
-    pub fn get_descriptor(self: &Self) -> Arc<gatt::descriptor::Descriptor> {
-        if let GattDataType::Descriptor(ref descriptor) = self {
-            return descriptor.clone();
+    pub fn get_descriptor(self: &Self) -> Option<Arc<gatt::descriptor::Descriptor>> {
+        match self {
+            GattDataType::Descriptor(descriptor) => Some(descriptor.clone()),
+            _ => None,
         }
-        panic!("GattDataType is not a Descriptor!");
+    }
+
+    // Looks up the characteristic behind `path`, as an error reply if there is none
+    pub fn characteristic(
+        tree: &mut Tree,
+        path: &Path<'static>,
+    ) -> Result<Arc<gatt::characteristic::Characteristic>, MethodErr> {
+        tree.data_mut::<GattDataType>(path)
+            .and_then(|data| data.get_characteristic())
+            .ok_or_else(|| MethodErr::no_path(path))
+    }
+
+    // Looks up the descriptor behind `path`, as an error reply if there is none
+    pub fn descriptor(
+        tree: &mut Tree,
+        path: &Path<'static>,
+    ) -> Result<Arc<gatt::descriptor::Descriptor>, MethodErr> {
+        tree.data_mut::<GattDataType>(path)
+            .and_then(|data| data.get_descriptor())
+            .ok_or_else(|| MethodErr::no_path(path))
     }
 }
 
@@ -1,13 +1,67 @@
-use std::fmt;
-use std::sync::Arc;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either, Shared},
+    prelude::*,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
 
-use dbus::{nonblock::SyncConnection, Path};
+use super::{
+    bus::{self, BusConfig, Driver},
+    common::Tree,
+    constants::{BLUEZ_DBUS_TIMEOUT, DBUS_IFACE, DBUS_PATH, DBUS_PEER_IFACE, DBUS_SERVICE_NAME},
+};
+use crate::{lock::LockExt, Error, ErrorKind, ErrorType};
+use dbus::{
+    arg::{AppendAll, ReadAll},
+    channel::{MatchingReceiver, Sender, Token},
+    message::MatchRule,
+    nonblock::{Proxy, SyncConnection},
+    strings::{Interface, Member},
+    Message, Path,
+};
 
-use super::constants::{BLUEZ_DBUS_TIMEOUT, BLUEZ_SERVICE_NAME};
-use crate::Error;
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Resolves once the bus connection it was taken from is gone
+type LostSignal = Shared<oneshot::Receiver<()>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Lost { reason: String },
+    Restored,
+    BluezStopped,
+    BluezStarted,
+}
+
+struct Bus {
+    default: Arc<SyncConnection>,
+    lost: LostSignal,
+}
+
+struct RouteEntry {
+    match_rule: MatchRule<'static>,
+    tree: Arc<Mutex<Tree>>,
+    token: Token,
+}
 
 pub struct Connection {
-    pub default: Arc<SyncConnection>,
+    config: BusConfig,
+    bus: Mutex<Bus>,
+    routes: Mutex<BTreeMap<usize, RouteEntry>>,
+    next_route: Mutex<usize>,
+    listeners: Mutex<Vec<mpsc::UnboundedSender<ConnectionEvent>>>,
+    auto_reconnect: AtomicBool,
+    // Dropping this ends the watcher, which owns the connection's I/O
+    _closed: oneshot::Sender<()>,
 }
 
 impl fmt::Debug for Connection {
@@ -17,17 +71,319 @@ impl fmt::Debug for Connection {
 }
 
 impl<'a> Connection {
-    pub fn new() -> Result<Self, Error> {
-        let (resource, default) = dbus_tokio::connection::new_system_sync()?;
-        tokio::spawn(async {
-            let err = resource.await;
-            panic!("Lost connection to D-Bus: {}", err);
+    pub fn new(config: BusConfig) -> Result<Arc<Self>, Error> {
+        let (driver, default) = bus::connect(&config.address)?;
+        let (lost_sender, lost) = oneshot::channel();
+        let (closed_sender, closed) = oneshot::channel();
+        let connection = Arc::new(Connection {
+            config,
+            bus: Mutex::new(Bus {
+                default,
+                lost: lost.shared(),
+            }),
+            routes: Mutex::new(BTreeMap::new()),
+            next_route: Mutex::new(0),
+            listeners: Mutex::new(Vec::new()),
+            auto_reconnect: AtomicBool::new(false),
+            _closed: closed_sender,
         });
+        tokio::spawn(Connection::watch(
+            Arc::downgrade(&connection),
+            driver,
+            lost_sender,
+            closed,
+        ));
+        connection.watch_bluez(&connection.bus.lock_or_recover().default);
+        Ok(connection)
+    }
+
+    pub fn get_bluez_proxy(&'a self, path: &'a Path) -> BluezProxy<'a> {
+        let bus = self.bus.lock_or_recover();
+        BluezProxy {
+            proxy: Proxy::new(
+                self.config.service_name.clone(),
+                path,
+                BLUEZ_DBUS_TIMEOUT,
+                bus.default.clone(),
+            ),
+            lost: bus.lost.clone(),
+        }
+    }
+
+    // Sends a method call to BlueZ without waiting for the reply, for cleanup where nothing can be awaited
+    pub fn call_bluez_no_reply<A: AppendAll>(
+        &self,
+        path: &Path,
+        interface: &str,
+        member: &str,
+        args: A,
+    ) {
+        if let Ok(mut msg) =
+            Message::new_method_call(&*self.config.service_name, path, interface, member)
+        {
+            msg.append_all(args);
+            msg.set_no_reply(true);
+            let _ = self.send(msg);
+        }
+    }
+
+    // Sends `message` and resolves once the bus daemon has read it, going by the reply to a Ping
+    // sent right after it: a connection's messages reach the bus in order. libdbus queues
+    // outgoing messages without limit, so this is how senders hold back.
+    pub async fn send_delivered(&self, message: Message) -> Result<(), Error> {
+        let (connection, lost) = {
+            let bus = self.bus.lock_or_recover();
+            (bus.default.clone(), bus.lost.clone())
+        };
+        if connection.send(message).is_err() {
+            return Err(connection_lost_error());
+        }
+        let proxy = Proxy::new(DBUS_SERVICE_NAME, DBUS_PATH, BLUEZ_DBUS_TIMEOUT, connection);
+        let ping = proxy.method_call::<(), _, _, _>(DBUS_PEER_IFACE, "Ping", ());
+        match future::select(ping, lost).await {
+            Either::Left((result, _)) => result.map_err(From::from),
+            Either::Right(_) => Err(connection_lost_error()),
+        }
+    }
+
+    pub fn events(&self) -> mpsc::UnboundedReceiver<ConnectionEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.listeners.lock_or_recover().push(sender);
+        receiver
+    }
+
+    pub fn set_auto_reconnect(&self, auto_reconnect: bool) {
+        self.auto_reconnect.store(auto_reconnect, Ordering::Relaxed);
+    }
+
+    // Hands method calls for `path`, and everything below it if `path_is_namespace`, to `tree`
+    pub fn route(
+        self: &Arc<Self>,
+        path: Path<'static>,
+        path_is_namespace: bool,
+        tree: Arc<Mutex<Tree>>,
+    ) -> Route {
+        let mut match_rule = MatchRule::new_method_call();
+        match_rule.path = Some(path.clone());
+        match_rule.path_is_namespace = path_is_namespace;
+
+        let id = {
+            let mut next_route = self.next_route.lock_or_recover();
+            *next_route += 1;
+            *next_route
+        };
+        let token = start_route(
+            &self.bus.lock_or_recover().default,
+            match_rule.clone(),
+            tree.clone(),
+        );
+        self.routes.lock_or_recover().insert(
+            id,
+            RouteEntry {
+                match_rule,
+                tree,
+                token,
+            },
+        );
+
+        Route {
+            connection: self.clone(),
+            path,
+            id,
+        }
+    }
+
+    async fn watch(
+        connection: Weak<Connection>,
+        mut driver: Driver,
+        mut lost_sender: oneshot::Sender<()>,
+        mut closed: oneshot::Receiver<()>,
+    ) {
+        loop {
+            let err = match future::select(driver, &mut closed).await {
+                Either::Left((err, _)) => err,
+                Either::Right(_) => return,
+            };
+            let _ = lost_sender.send(());
+
+            let connection = match connection.upgrade() {
+                Some(connection) => connection,
+                None => return,
+            };
+            log::warn!("Lost connection to D-Bus: {}", err);
+            connection.emit(ConnectionEvent::Lost {
+                reason: err.to_string(),
+            });
+            if !connection.auto_reconnect.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let mut delay = RECONNECT_MIN_DELAY;
+            let (new_driver, new_lost_sender) = loop {
+                tokio::time::delay_for(delay).await;
+                match connection.reconnect() {
+                    Ok(reconnected) => break reconnected,
+                    Err(err) => {
+                        log::warn!("Could not reconnect to D-Bus: {}", err);
+                        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                    }
+                }
+            };
+            driver = new_driver;
+            lost_sender = new_lost_sender;
+            connection.emit(ConnectionEvent::Restored);
+        }
+    }
+
+    fn reconnect(self: &Arc<Self>) -> Result<(Driver, oneshot::Sender<()>), Error> {
+        let (driver, default) = bus::connect(&self.config.address)?;
+        let (lost_sender, lost) = oneshot::channel();
+        self.watch_bluez(&default);
 
-        Ok(Connection { default })
+        // Every tree keeps its route on the new connection
+        for entry in self.routes.lock_or_recover().values_mut() {
+            entry.token = start_route(&default, entry.match_rule.clone(), entry.tree.clone());
+        }
+        *self.bus.lock_or_recover() = Bus {
+            default,
+            lost: lost.shared(),
+        };
+        Ok((driver, lost_sender))
     }
 
-    pub fn get_bluez_proxy(&'a self, path: &'a Path) -> dbus::nonblock::Proxy<&'a SyncConnection> {
-        dbus::nonblock::Proxy::new(BLUEZ_SERVICE_NAME, path, BLUEZ_DBUS_TIMEOUT, &self.default)
+    // bluetoothd drops everything registered with it when it exits, so its
+    // comings and goings are reported as events
+    fn watch_bluez(self: &Arc<Self>, default: &Arc<SyncConnection>) {
+        let mut match_rule = MatchRule::new_signal(DBUS_IFACE, "NameOwnerChanged");
+        match_rule.sender = Some(DBUS_SERVICE_NAME.into());
+        match_rule.path = Some(DBUS_PATH.into());
+
+        let connection = Arc::downgrade(self);
+        default.start_receive(
+            match_rule.clone(),
+            Box::new(move |msg, _conn| {
+                let connection = match connection.upgrade() {
+                    Some(connection) => connection,
+                    None => return false,
+                };
+                if let Ok((name, old_owner, new_owner)) = msg.read3::<&str, &str, &str>() {
+                    if name == connection.config.service_name {
+                        if !old_owner.is_empty() {
+                            connection.emit(ConnectionEvent::BluezStopped);
+                        }
+                        if !new_owner.is_empty() {
+                            connection.emit(ConnectionEvent::BluezStarted);
+                        }
+                    }
+                }
+                true
+            }),
+        );
+
+        // Only ask the bus for changes to org.bluez, not every name on it
+        let match_str = format!(
+            "{},arg0='{}'",
+            match_rule.match_str(),
+            self.config.service_name
+        );
+        let default = default.clone();
+        tokio::spawn(async move {
+            if let Err(err) = default.add_match_no_cb(&match_str).await {
+                log::warn!("Could not watch for bluetoothd restarts: {}", err);
+            }
+        });
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        self.listeners
+            .lock_or_recover()
+            .retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+impl Sender for Connection {
+    fn send(&self, msg: Message) -> Result<u32, ()> {
+        self.bus.lock_or_recover().default.send(msg)
+    }
+}
+
+fn start_route(
+    connection: &SyncConnection,
+    match_rule: MatchRule<'static>,
+    tree: Arc<Mutex<Tree>>,
+) -> Token {
+    connection.start_receive(
+        match_rule,
+        Box::new(move |msg, conn| {
+            if tree.lock_or_recover().handle_message(msg, conn).is_err() {
+                log::warn!("Could not handle D-Bus message");
+            }
+            true
+        }),
+    )
+}
+
+fn connection_lost_error() -> Error {
+    Error::new(
+        ErrorKind::BackendDisconnected,
+        "The connection to D-Bus was lost",
+        ErrorType::Bluez,
+    )
+}
+
+// Proxy whose calls fail as soon as the connection is lost, instead of waiting for a reply
+pub struct BluezProxy<'a> {
+    proxy: Proxy<'a, Arc<SyncConnection>>,
+    lost: LostSignal,
+}
+
+impl BluezProxy<'_> {
+    // Builds the call up front so that arguments which aren't `Send` are never held across an await
+    pub fn method_call<'i, 'm, R, A, I, M>(
+        &self,
+        interface: I,
+        member: M,
+        args: A,
+    ) -> impl Future<Output = Result<R, Error>>
+    where
+        R: ReadAll + 'static,
+        A: AppendAll,
+        I: Into<Interface<'i>>,
+        M: Into<Member<'m>>,
+    {
+        let reply = self.proxy.method_call(interface, member, args);
+        let lost = self.lost.clone();
+        async move {
+            match future::select(reply, lost).await {
+                Either::Left((result, _)) => result.map_err(From::from),
+                Either::Right(_) => Err(connection_lost_error()),
+            }
+        }
+    }
+}
+
+// Stops routing method calls to the tree once dropped, so peripherals sharing
+// a connection never see each other's traffic after they go away
+pub struct Route {
+    connection: Arc<Connection>,
+    path: Path<'static>,
+    id: usize,
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Route({})", self.path)
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        if let Some(entry) = self.connection.routes.lock_or_recover().remove(&self.id) {
+            self.connection
+                .bus
+                .lock_or_recover()
+                .default
+                .stop_receive(entry.token);
+        }
     }
 }
@@ -1,8 +1,14 @@
 use std::time::Duration;
 
+pub const DBUS_SERVICE_NAME: &str = "org.freedesktop.DBus";
+pub const DBUS_PATH: &str = "/org/freedesktop/DBus";
+pub const DBUS_IFACE: &str = "org.freedesktop.DBus";
+pub const DBUS_PEER_IFACE: &str = "org.freedesktop.DBus.Peer";
 pub const DBUS_PROPERTIES_IFACE: &str = "org.freedesktop.DBus.Properties";
 pub const DBUS_OBJECTMANAGER_IFACE: &str = "org.freedesktop.DBus.ObjectManager";
 
+pub const DBUS_ERROR_INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+
 pub const BLUEZ_SERVICE_NAME: &str = "org.bluez";
 
 pub const ADAPTER_IFACE: &str = "org.bluez.Adapter1";
@@ -16,12 +22,14 @@ pub const GATT_DESCRIPTOR_IFACE: &str = "org.bluez.GattDescriptor1";
 pub const GATT_GATT_MANAGER_IFACE: &str = "org.bluez.GattManager1";
 
 pub const BLUEZ_ERROR_FAILED: &str = "org.bluez.Error.Failed";
-// pub const BLUEZ_ERROR_INPROGRESS: &str = "org.bluez.Error.InProgress";
+pub const BLUEZ_ERROR_INPROGRESS: &str = "org.bluez.Error.InProgress";
 // pub const BLUEZ_ERROR_NOTPERMITTED: &str = "org.bluez.Error.NotPermitted";
 // pub const BLUEZ_ERROR_NOTAUTHORIZED: &str = "org.bluez.Error.NotAuthorized";
-// pub const BLUEZ_ERROR_INVALIDOFFSET: &str = "org.bluez.Error.InvalidOffset";
+pub const BLUEZ_ERROR_INVALIDOFFSET: &str = "org.bluez.Error.InvalidOffset";
+pub const BLUEZ_ERROR_INVALIDVALUELENGTH: &str = "org.bluez.Error.InvalidValueLength";
 pub const BLUEZ_ERROR_NOTSUPPORTED: &str = "org.bluez.Error.NotSupported";
 
+// Generated namespaces for each `Peripheral` live under this path
 pub const PATH_BASE: &str = "/org/bluez/example";
 
 pub const BLUEZ_DBUS_TIMEOUT: Duration = Duration::from_secs(30);
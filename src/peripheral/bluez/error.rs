@@ -1,35 +1,72 @@
-use crate::{Error, ErrorType};
+use super::constants::DBUS_ERROR_INVALID_ARGS;
+use crate::{advertisement::ad_structure::AdError, Error, ErrorKind, ErrorType};
 use dbus::{arg::TypeMismatchError as DbusTypeMismatchError, Error as DbusError};
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+fn dbus_error_kind(name: &str) -> ErrorKind {
+    match name {
+        "org.bluez.Error.AlreadyExists" => ErrorKind::AlreadyRegistered,
+        "org.bluez.Error.DoesNotExist" => ErrorKind::NotRegistered,
+        // BlueZ refuses to register anything while the adapter is off
+        "org.bluez.Error.NotReady" => ErrorKind::NotPowered,
+        "org.bluez.Error.NotPermitted"
+        | "org.bluez.Error.NotAuthorized"
+        | "org.freedesktop.DBus.Error.AccessDenied" => ErrorKind::PermissionDenied,
+        "org.freedesktop.DBus.Error.NoReply" | "org.freedesktop.DBus.Error.Timeout" => {
+            ErrorKind::Timeout
+        }
+        "org.bluez.Error.InvalidArguments" | DBUS_ERROR_INVALID_ARGS => {
+            ErrorKind::InvalidDefinition
+        }
+        "org.bluez.Error.NotSupported" => ErrorKind::NotSupported,
+        "org.freedesktop.DBus.Error.ServiceUnknown"
+        | "org.freedesktop.DBus.Error.NameHasNoOwner"
+        | "org.freedesktop.DBus.Error.NoServer"
+        | "org.freedesktop.DBus.Error.Disconnected" => ErrorKind::BackendDisconnected,
+        name => ErrorKind::Platform(name.to_owned()),
+    }
+}
 
 impl From<DbusError> for Error {
     fn from(dbus_error: DbusError) -> Error {
-        Error::new(
-            dbus_error.name().unwrap_or(""),
-            dbus_error.message().unwrap_or(""),
-            ErrorType::Bluez,
-        )
+        let kind = dbus_error_kind(dbus_error.name().unwrap_or(""));
+        let description = dbus_error.message().unwrap_or("").to_owned();
+        Error::new(kind, description, ErrorType::Bluez).with_source(dbus_error)
     }
 }
 
 impl From<DbusTypeMismatchError> for Error {
     fn from(dbus_type_mismatch_error: DbusTypeMismatchError) -> Error {
-        Error::from(DbusError::from(dbus_type_mismatch_error))
+        Error::new(
+            dbus_error_kind(DBUS_ERROR_INVALID_ARGS),
+            dbus_type_mismatch_error.to_string(),
+            ErrorType::Bluez,
+        )
+        .with_source(dbus_type_mismatch_error)
     }
 }
 
 impl From<IoError> for Error {
     fn from(io_error: IoError) -> Error {
-        Error::new(
-            format!("std::io::Error: {:?}", io_error.kind()),
-            format!("{:?}", io_error),
-            ErrorType::Bluez,
-        )
+        let kind = match io_error.kind() {
+            IoErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            IoErrorKind::TimedOut => ErrorKind::Timeout,
+            IoErrorKind::NotFound | IoErrorKind::ConnectionRefused => {
+                ErrorKind::BackendDisconnected
+            }
+            kind => ErrorKind::Platform(format!("std::io::Error: {:?}", kind)),
+        };
+        Error::new(kind, io_error.to_string(), ErrorType::Bluez).with_source(io_error)
     }
 }
 
-impl From<()> for Error {
-    fn from(_: ()) -> Error {
-        Error::new("no name", "no description", ErrorType::Bluez)
+impl From<AdError> for Error {
+    fn from(ad_error: AdError) -> Error {
+        Error::new(
+            ErrorKind::InvalidDefinition,
+            ad_error.to_string(),
+            ErrorType::Bluez,
+        )
+        .with_source(ad_error)
     }
 }
@@ -0,0 +1,484 @@
+use dbus::{
+    arg::{RefArg, Variant},
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    nonblock::{stdintf::org_freedesktop_dbus::RequestNameReply, Proxy, SyncConnection},
+    Path,
+};
+use dbus_crossroads::{Context, Crossroads, MethodErr};
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    prelude::*,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+use super::{
+    bus::{self, BusConfig},
+    constants::{
+        ADAPTER_IFACE, BLUEZ_DBUS_TIMEOUT, DBUS_OBJECTMANAGER_IFACE, DBUS_PROPERTIES_IFACE,
+        GATT_CHARACTERISTIC_IFACE, GATT_GATT_MANAGER_IFACE, LE_ADVERTISING_MANAGER_IFACE,
+    },
+};
+use crate::{lock::LockExt, Error, ErrorKind, ErrorType};
+
+const ADAPTER_PATH: &str = "/org/bluez/hci0";
+// The central the fake plays
+const DEVICE_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55";
+const SUPPORTED_INSTANCES: u8 = 5;
+
+const BLUEZ_ERROR_ALREADY_EXISTS: &str = "org.bluez.Error.AlreadyExists";
+const BLUEZ_ERROR_DOES_NOT_EXIST: &str = "org.bluez.Error.DoesNotExist";
+const BLUEZ_ERROR_NOT_PERMITTED: &str = "org.bluez.Error.NotPermitted";
+
+type OptionsMap = HashMap<String, Variant<Box<dyn RefArg>>>;
+type ManagedObjects =
+    HashMap<Path<'static>, HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>>;
+
+// An object registered with the fake, and the unique bus name of whoever registered it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeRegistration {
+    pub owner: String,
+    pub path: Path<'static>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    powered: bool,
+    applications: Vec<FakeRegistration>,
+    advertisements: Vec<FakeRegistration>,
+}
+
+type State = Arc<Mutex<FakeState>>;
+
+// Stands in for bluetoothd on a bus of your choosing, with a single adapter at /org/bluez/hci0.
+// It records GATT applications and advertisements registered with it, and plays the central
+// by calling into registered applications. Dropping it drops its connection, like bluetoothd
+// exiting.
+pub struct FakeBluez {
+    connection: Arc<SyncConnection>,
+    service_name: String,
+    state: State,
+    // Dropping this ends the connection's driver
+    _closed: oneshot::Sender<()>,
+}
+
+impl fmt::Debug for FakeBluez {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FakeBluez")
+    }
+}
+
+impl FakeBluez {
+    pub async fn start(bus: &BusConfig) -> Result<Self, Error> {
+        let (driver, connection) = bus::connect(&bus.address)?;
+        let (closed_sender, closed) = oneshot::channel();
+        tokio::spawn(future::select(driver, closed).map(|_| ()));
+
+        let state = State::default();
+        let tree = Mutex::new(tree(&state));
+        let root_state = state.clone();
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                // The tree can't serve an object manager at the root path, so it's answered here
+                if msg.path().as_deref() == Some("/")
+                    && msg.interface().as_deref() == Some(DBUS_OBJECTMANAGER_IFACE)
+                    && msg.member().as_deref() == Some("GetManagedObjects")
+                {
+                    let _ = conn.send(msg.method_return().append1(managed_objects(&root_state)));
+                } else if tree.lock_or_recover().handle_message(msg, conn).is_err() {
+                    log::warn!("Could not handle D-Bus message");
+                }
+                true
+            }),
+        );
+
+        let reply = connection
+            .request_name(&*bus.service_name, false, true, true)
+            .await?;
+        if reply != RequestNameReply::PrimaryOwner {
+            return Err(Error::new(
+                ErrorKind::AlreadyRegistered,
+                format!("{} is already owned by someone else", bus.service_name),
+                ErrorType::Bluez,
+            ));
+        }
+
+        Ok(FakeBluez {
+            connection,
+            service_name: bus.service_name.clone(),
+            state,
+            _closed: closed_sender,
+        })
+    }
+
+    // Leaves the bus name and takes it again, forgetting every registration on the way, like
+    // bluetoothd being restarted
+    pub async fn restart(&self) -> Result<(), Error> {
+        self.connection.release_name(&*self.service_name).await?;
+        {
+            let mut state = self.state.lock_or_recover();
+            state.applications.clear();
+            state.advertisements.clear();
+        }
+        self.connection
+            .request_name(&*self.service_name, false, true, true)
+            .await?;
+        Ok(())
+    }
+
+    pub fn adapter_path(&self) -> Path<'static> {
+        ADAPTER_PATH.into()
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.state.lock_or_recover().powered
+    }
+
+    pub fn applications(&self) -> Vec<FakeRegistration> {
+        self.state.lock_or_recover().applications.clone()
+    }
+
+    pub fn advertisements(&self) -> Vec<FakeRegistration> {
+        self.state.lock_or_recover().advertisements.clone()
+    }
+
+    // Looks through the registered applications for a characteristic, the way BlueZ discovers them
+    pub async fn find_characteristic(&self, uuid: &Uuid) -> Result<Option<Path<'static>>, Error> {
+        let uuid = uuid.to_string();
+        for application in self.applications() {
+            let proxy = Proxy::new(
+                application.owner,
+                application.path,
+                BLUEZ_DBUS_TIMEOUT,
+                self.connection.clone(),
+            );
+            let (objects,): (ManagedObjects,) = proxy
+                .method_call(DBUS_OBJECTMANAGER_IFACE, "GetManagedObjects", ())
+                .await?;
+            let found = objects.into_iter().find_map(|(path, interfaces)| {
+                interfaces
+                    .get(GATT_CHARACTERISTIC_IFACE)
+                    .and_then(|properties| properties.get("UUID"))
+                    .and_then(|value| value.0.as_str())
+                    .filter(|value| *value == uuid)
+                    .map(|_| path)
+            });
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn read_value(
+        &self,
+        characteristic: &Path<'static>,
+        offset: u16,
+    ) -> Result<Vec<u8>, Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+        let (value,): (Vec<u8>,) = proxy
+            .method_call(
+                GATT_CHARACTERISTIC_IFACE,
+                "ReadValue",
+                (request_options(offset),),
+            )
+            .await?;
+        Ok(value)
+    }
+
+    pub async fn write_value(
+        &self,
+        characteristic: &Path<'static>,
+        data: &[u8],
+        offset: u16,
+    ) -> Result<(), Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+        proxy
+            .method_call(
+                GATT_CHARACTERISTIC_IFACE,
+                "WriteValue",
+                (data.to_vec(), request_options(offset)),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Writes without response, like a central's write command
+    pub async fn write_command(
+        &self,
+        characteristic: &Path<'static>,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+        let mut options = request_options(0);
+        options.insert("type", Variant(Box::new("command".to_owned())));
+        let call = proxy.method_call::<(), _, _, _>(
+            GATT_CHARACTERISTIC_IFACE,
+            "WriteValue",
+            (data.to_vec(), options),
+        );
+        call.await?;
+        Ok(())
+    }
+
+    // Writes `data` in parts of `part_size` bytes, the way BlueZ executes a long write
+    pub async fn write_long_value(
+        &self,
+        characteristic: &Path<'static>,
+        data: &[u8],
+        part_size: usize,
+    ) -> Result<(), Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+        for (index, part) in data.chunks(part_size.max(1)).enumerate() {
+            let mut options = request_options((index * part_size) as u16);
+            options.insert("type", Variant(Box::new("reliable".to_owned())));
+            let call = proxy.method_call::<(), _, _, _>(
+                GATT_CHARACTERISTIC_IFACE,
+                "WriteValue",
+                (part.to_vec(), options),
+            );
+            call.await?;
+        }
+        Ok(())
+    }
+
+    // Subscribes like a central would; every notified or indicated value comes out of the receiver
+    pub async fn start_notify(
+        &self,
+        characteristic: &Path<'static>,
+    ) -> Result<mpsc::UnboundedReceiver<Vec<u8>>, Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+
+        let mut match_rule = MatchRule::new_signal(DBUS_PROPERTIES_IFACE, "PropertiesChanged");
+        match_rule.path = Some(characteristic.clone());
+        self.connection
+            .add_match_no_cb(&match_rule.match_str())
+            .await?;
+        let (sender, receiver) = mpsc::unbounded();
+        let token = self.connection.start_receive(
+            match_rule,
+            Box::new(move |msg, _conn| {
+                let value = msg
+                    .read2::<&str, OptionsMap>()
+                    .ok()
+                    .and_then(|(_, changed)| changed.get("Value").and_then(value_bytes));
+                match value {
+                    Some(value) => sender.unbounded_send(value).is_ok(),
+                    None => !sender.is_closed(),
+                }
+            }),
+        );
+
+        let result: Result<(), _> = proxy
+            .method_call(GATT_CHARACTERISTIC_IFACE, "StartNotify", ())
+            .await;
+        if let Err(err) = result {
+            self.connection.stop_receive(token);
+            return Err(err.into());
+        }
+        Ok(receiver)
+    }
+
+    pub async fn stop_notify(&self, characteristic: &Path<'static>) -> Result<(), Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+        proxy
+            .method_call(GATT_CHARACTERISTIC_IFACE, "StopNotify", ())
+            .await?;
+        Ok(())
+    }
+
+    // Acknowledges an indication like a central would
+    pub async fn confirm(&self, characteristic: &Path<'static>) -> Result<(), Error> {
+        let proxy = self.characteristic_proxy(characteristic)?;
+        proxy
+            .method_call(GATT_CHARACTERISTIC_IFACE, "Confirm", ())
+            .await?;
+        Ok(())
+    }
+
+    fn characteristic_proxy<'a>(
+        &self,
+        characteristic: &'a Path<'static>,
+    ) -> Result<Proxy<'a, Arc<SyncConnection>>, Error> {
+        let application = self
+            .applications()
+            .into_iter()
+            .find(|application| characteristic.starts_with(&format!("{}/", application.path)))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotRegistered,
+                    format!("No registered application exports {}", characteristic),
+                    ErrorType::Bluez,
+                )
+            })?;
+        Ok(Proxy::new(
+            application.owner,
+            characteristic,
+            BLUEZ_DBUS_TIMEOUT,
+            self.connection.clone(),
+        ))
+    }
+}
+
+fn tree(state: &State) -> Crossroads {
+    let mut tree = Crossroads::new();
+
+    let adapter = tree.register::<State, _, _>(ADAPTER_IFACE, |b| {
+        b.property("Powered")
+            .get(|_ctx, state| Ok(state.lock_or_recover().powered))
+            .set(|_ctx, state, powered| {
+                state.lock_or_recover().powered = powered;
+                Ok(Some(powered))
+            });
+    });
+    let gatt_manager = tree.register::<State, _, _>(GATT_GATT_MANAGER_IFACE, |b| {
+        b.method(
+            "RegisterApplication",
+            ("application", "options"),
+            (),
+            |ctx, state, (path, _options): (Path<'static>, OptionsMap)| {
+                register(ctx, &mut state.lock_or_recover().applications, path)
+            },
+        );
+        b.method(
+            "UnregisterApplication",
+            ("application",),
+            (),
+            |ctx, state, (path,): (Path<'static>,)| {
+                unregister(ctx, &mut state.lock_or_recover().applications, path)
+            },
+        );
+    });
+    let advertising_manager = tree.register::<State, _, _>(LE_ADVERTISING_MANAGER_IFACE, |b| {
+        b.method(
+            "RegisterAdvertisement",
+            ("advertisement", "options"),
+            (),
+            |ctx, state, (path, _options): (Path<'static>, OptionsMap)| {
+                let mut state = state.lock_or_recover();
+                if state.advertisements.len() >= usize::from(SUPPORTED_INSTANCES) {
+                    return Err(MethodErr::from((
+                        BLUEZ_ERROR_NOT_PERMITTED,
+                        "Maximum advertisements reached",
+                    )));
+                }
+                register(ctx, &mut state.advertisements, path)
+            },
+        );
+        b.method(
+            "UnregisterAdvertisement",
+            ("advertisement",),
+            (),
+            |ctx, state, (path,): (Path<'static>,)| {
+                unregister(ctx, &mut state.lock_or_recover().advertisements, path)
+            },
+        );
+        b.property("ActiveInstances")
+            .get(|_ctx, state| Ok(state.lock_or_recover().advertisements.len() as u8));
+        b.property("SupportedInstances").get(|_ctx, state| {
+            let active = state.lock_or_recover().advertisements.len() as u8;
+            Ok(SUPPORTED_INSTANCES.saturating_sub(active))
+        });
+        b.property("SupportedIncludes").get(|_ctx, _state| {
+            Ok(vec![
+                "tx-power".to_owned(),
+                "appearance".to_owned(),
+                "local-name".to_owned(),
+            ])
+        });
+        b.property("SupportedSecondaryChannels")
+            .get(|_ctx, _state| Ok(Vec::<String>::new()));
+        b.property("SupportedFeatures")
+            .get(|_ctx, _state| Ok(Vec::<String>::new()));
+    });
+
+    tree.insert(
+        ADAPTER_PATH,
+        &[adapter, gatt_manager, advertising_manager],
+        state.clone(),
+    );
+    tree
+}
+
+fn managed_objects(state: &State) -> ManagedObjects {
+    let mut adapter = HashMap::new();
+    adapter.insert(
+        "Powered".to_owned(),
+        Variant(Box::new(state.lock_or_recover().powered) as Box<dyn RefArg>),
+    );
+    let mut interfaces = HashMap::new();
+    interfaces.insert(ADAPTER_IFACE.to_owned(), adapter);
+    interfaces.insert(GATT_GATT_MANAGER_IFACE.to_owned(), HashMap::new());
+    interfaces.insert(LE_ADVERTISING_MANAGER_IFACE.to_owned(), HashMap::new());
+
+    let mut objects = HashMap::new();
+    objects.insert(ADAPTER_PATH.into(), interfaces);
+    objects
+}
+
+fn register(
+    ctx: &mut Context,
+    registrations: &mut Vec<FakeRegistration>,
+    path: Path<'static>,
+) -> Result<(), MethodErr> {
+    let registration = FakeRegistration {
+        owner: sender(ctx),
+        path,
+    };
+    if registrations.contains(&registration) {
+        return Err(MethodErr::from((
+            BLUEZ_ERROR_ALREADY_EXISTS,
+            "Already Exists",
+        )));
+    }
+    registrations.push(registration);
+    Ok(())
+}
+
+fn unregister(
+    ctx: &mut Context,
+    registrations: &mut Vec<FakeRegistration>,
+    path: Path<'static>,
+) -> Result<(), MethodErr> {
+    let registration = FakeRegistration {
+        owner: sender(ctx),
+        path,
+    };
+    let index = registrations
+        .iter()
+        .position(|registered| *registered == registration)
+        .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_DOES_NOT_EXIST, "Does Not Exist")))?;
+    registrations.remove(index);
+    Ok(())
+}
+
+fn sender(ctx: &Context) -> String {
+    ctx.message()
+        .sender()
+        .map(|sender| sender.to_string())
+        .unwrap_or_default()
+}
+
+// What BlueZ passes to ReadValue and WriteValue
+fn request_options(offset: u16) -> HashMap<&'static str, Variant<Box<dyn RefArg>>> {
+    let mut options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+    options.insert("offset", Variant(Box::new(offset)));
+    options.insert("device", Variant(Box::new(Path::from(DEVICE_PATH))));
+    options
+}
+
+fn value_bytes(value: &Variant<Box<dyn RefArg>>) -> Option<Vec<u8>> {
+    value.0.as_iter().map(|bytes| {
+        bytes
+            .filter_map(|byte| byte.as_u64())
+            .map(|byte| byte as u8)
+            .collect()
+    })
+}
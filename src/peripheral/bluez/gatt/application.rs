@@ -4,11 +4,7 @@ use dbus::{
 };
 use std::{collections::HashMap, sync::Arc};
 
-use super::super::{
-    common,
-    constants::{GATT_GATT_MANAGER_IFACE, PATH_BASE},
-    Connection, Error,
-};
+use super::super::{common, constants::GATT_GATT_MANAGER_IFACE, Connection, Error};
 
 #[derive(Debug, Clone)]
 pub struct Application {
@@ -22,12 +18,13 @@ impl Application {
         connection: Arc<Connection>,
         tree: &mut common::Tree,
         adapter: Path<'static>,
+        object_path: Path<'static>,
     ) -> Self {
-        tree.insert(PATH_BASE, &[tree.object_manager()], ());
+        tree.insert(object_path.clone(), &[tree.object_manager()], ());
 
         Application {
             connection,
-            object_path: PATH_BASE.into(),
+            object_path,
             adapter,
         }
     }
@@ -44,7 +41,15 @@ impl Application {
                 ),
             )
             .await
-            .map_err(From::from)
+    }
+
+    pub fn release(&self) {
+        self.connection.call_bluez_no_reply(
+            &self.adapter,
+            GATT_GATT_MANAGER_IFACE,
+            "UnregisterApplication",
+            (&self.object_path,),
+        );
     }
 
     pub async fn unregister(self: &Self) -> Result<(), Error> {
@@ -56,6 +61,5 @@ impl Application {
                 (&self.object_path,),
             )
             .await
-            .map_err(From::from)
     }
 }
@@ -1,28 +1,32 @@
 use dbus::{
-    arg::{RefArg, Variant},
-    channel::Sender,
-    nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
-    tree::MethodErr,
-    Message, Path,
+    arg::Variant, nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
+    tree::MethodErr, Message, Path,
 };
-use futures::{
-    channel::{mpsc, oneshot},
-    prelude::*,
+use futures::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
 };
-use std::{collections::HashMap, sync::Arc};
 
 use super::{
     super::{
-        common,
-        common::GattDataType,
-        constants::{BLUEZ_ERROR_FAILED, BLUEZ_ERROR_NOTSUPPORTED, GATT_CHARACTERISTIC_IFACE},
+        super::event::EventDispatcher,
+        common::{self, GattDataType},
+        constants::{BLUEZ_ERROR_NOTSUPPORTED, DBUS_PROPERTIES_IFACE, GATT_CHARACTERISTIC_IFACE},
         Connection,
     },
     flags::Flags,
+    long_value::LongValueState,
+    request::{self, Attribute, OptionsMap, RequestOptions},
+};
+use crate::{
+    gatt::{
+        self, handler,
+        notification::{self, Notifier, NotifyError},
+    },
+    lock::LockExt,
+    Error,
 };
-use crate::{gatt, Error};
-
-type OptionsMap = HashMap<String, Variant<Box<dyn RefArg>>>;
 
 #[derive(Debug, Clone)]
 pub struct Characteristic {
@@ -36,73 +40,45 @@ impl Characteristic {
         characteristic: &Arc<gatt::characteristic::Characteristic>,
         service: &Path<'static>,
         index: u64,
+        events: &EventDispatcher,
     ) -> Result<Self, Error> {
         let object_path: Path = format!("{}/characteristic{:04}", service, index).into();
         let object_path_data = common::GattDataType::Characteristic(Arc::clone(characteristic));
-        // Setup a channel for notifications
-        let (message_sender, message_receiver) = mpsc::channel(1);
-        {
-            let object_path = object_path.clone();
-            let connection = Arc::clone(connection);
-            tokio::spawn(
-                message_receiver
-                    .map(move |notification: Vec<u8>| {
-                        // For notifications, BlueZ wants a PropertiesChanged
-                        // signal on the optional `Value` property. It doesn't
-                        // require that the property actually exists.
-                        let mut props = HashMap::new();
-                        props.insert("Value".to_owned(), Variant(Box::new(notification) as _));
-                        let signal = PropertiesPropertiesChanged {
-                            interface_name: GATT_CHARACTERISTIC_IFACE.to_string(),
-                            changed_properties: props,
-                            invalidated_properties: Vec::new(),
-                        };
-                        let mut signal_message = Message::signal(
-                            &object_path,
-                            &"org.freedesktop.DBus.Properties".into(),
-                            &"PropertiesChanged".into(),
-                        );
-                        signal_message.append_all(signal);
-                        connection.default.send(signal_message).ok();
-                    })
-                    .collect::<()>(),
-            );
-        }
+        // Only one central subscription is tracked by BlueZ per characteristic
+        let subscription: Arc<Mutex<Option<Notifier>>> = Arc::new(Mutex::new(None));
+        let attribute = Attribute {
+            uuid: characteristic.uuid,
+            response_timeout: characteristic.response_timeout.clone(),
+            long_values: characteristic
+                .long_values
+                .map(|long_values| Arc::new(LongValueState::new(long_values))),
+            events: events.clone(),
+        };
 
         let iface_token = tree.register::<GattDataType, _, _>(GATT_CHARACTERISTIC_IFACE, |b| {
-            let message_sender = message_sender.clone();
+            let connection = Arc::clone(connection);
+            let start_subscription = Arc::clone(&subscription);
+            let read_attribute = attribute.clone();
+            let write_attribute = attribute;
             b.method_with_cr_async(
                 "ReadValue",
                 ("options",),
                 ("value",),
-                |mut ctx, cr, (options,): (OptionsMap,)| {
-                    let offset = options.get("offset").and_then(RefArg::as_u64).unwrap_or(0) as u16;
-                    let characteristic = cr
-                        .data_mut::<GattDataType>(ctx.path())
-                        .unwrap()
-                        .get_characteristic();
+                move |mut ctx, cr, (options,): (OptionsMap,)| {
+                    let options = RequestOptions::parse(&options);
+                    let characteristic = GattDataType::characteristic(cr, ctx.path());
+                    let attribute = read_attribute.clone();
                     async move {
+                        let characteristic = characteristic?;
                         let event_sender = characteristic
                             .properties
                             .read
                             .clone()
                             .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
-                        let (sender, receiver) = oneshot::channel();
-                        event_sender
-                            .sender()
-                            .send(gatt::event::Event::ReadRequest(gatt::event::ReadRequest {
-                                offset,
-                                response: sender,
-                            }))
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))?;
-                        receiver
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))
-                            .and_then(|resp| match resp {
-                                gatt::event::Response::Success(value) => Ok((value,)),
-                                _ => Err(MethodErr::from((BLUEZ_ERROR_FAILED, ""))),
-                            })
+                        let event_sender =
+                            handler::route(&characteristic.handler, event_sender.sender());
+                        let value = attribute.read(event_sender, options).await?;
+                        Ok((value,))
                     }
                     .map(move |result| ctx.reply(result))
                 },
@@ -111,100 +87,120 @@ impl Characteristic {
                 "WriteValue",
                 ("data", "options"),
                 ("value",),
-                |mut ctx, cr, (data, options): (Vec<u8>, OptionsMap)| {
-                    let offset = options.get("offset").and_then(RefArg::as_u64).unwrap_or(0) as u16;
-                    let characteristic = cr
-                        .data_mut::<GattDataType>(ctx.path())
-                        .unwrap()
-                        .get_characteristic();
+                move |mut ctx, cr, (data, options): (Vec<u8>, OptionsMap)| {
+                    let options = RequestOptions::parse(&options);
+                    let characteristic = GattDataType::characteristic(cr, ctx.path());
+                    let attribute = write_attribute.clone();
                     async move {
+                        let characteristic = characteristic?;
                         let event_sender = characteristic
                             .properties
                             .write
                             .clone()
                             .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
-                        let (sender, receiver) = oneshot::channel();
-                        event_sender
-                            .sender()
-                            .send(gatt::event::Event::WriteRequest(
-                                gatt::event::WriteRequest {
-                                    data,
-                                    offset,
-                                    without_response: false,
-                                    response: sender,
-                                },
-                            ))
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))?;
-                        receiver
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))
-                            .and_then(|resp| match resp {
-                                gatt::event::Response::Success(value) => Ok((value,)),
-                                _ => Err(MethodErr::from((BLUEZ_ERROR_FAILED, ""))),
-                            })
+                        let event_sender =
+                            handler::route(&characteristic.handler, event_sender.sender());
+                        let value = attribute.write(event_sender, data, options).await?;
+                        Ok((value,))
                     }
                     .map(move |result| ctx.reply(result))
                 },
             );
             b.method_with_cr_async("StartNotify", (), (), move |mut ctx, cr, ()| {
-                let characteristic = cr
-                    .data_mut::<GattDataType>(ctx.path())
-                    .unwrap()
-                    .get_characteristic();
-                let message_sender = message_sender.clone();
+                let characteristic = GattDataType::characteristic(cr, ctx.path());
+                let connection = Arc::clone(&connection);
+                let subscription = Arc::clone(&start_subscription);
+                let object_path = ctx.path().clone();
                 async move {
-                    let (sender, mut receiver) = mpsc::channel(1);
+                    let characteristic = characteristic?;
+                    let event_sender = characteristic
+                        .properties
+                        .notify
+                        .clone()
+                        .or_else(|| characteristic.properties.indicate.clone())
+                        .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
+                    let event_sender = handler::route(&characteristic.handler, event_sender);
+                    let (notifier, notifications) =
+                        notification::channel(characteristic.notification_queue);
                     let notify_subscribe = gatt::event::NotifySubscribe {
-                        notification: sender,
+                        notification: notifier.clone(),
                     };
-                    tokio::spawn(async move {
-                        while let Some(notification) = receiver.next().await {
-                            let mut message_sender = message_sender.clone();
-                            let _ = message_sender.send(notification).await;
+                    // The subscription only starts once the handler has taken it
+                    if let Err(err) = request::deliver(
+                        event_sender,
+                        gatt::event::Event::NotifySubscribe(notify_subscribe),
+                    )
+                    .await
+                    {
+                        notifier.close();
+                        return Err(err);
+                    }
+                    if let Some(previous) = subscription.lock_or_recover().replace(notifier) {
+                        previous.close();
+                    }
+                    // Notifications stay in their queue, where its overflow policy applies, until
+                    // the bus has the one before them
+                    tokio::spawn(notifications.for_each(move |pending| {
+                        let signal = value_changed_signal(&object_path, pending.value.clone());
+                        let connection = Arc::clone(&connection);
+                        async move {
+                            let result = connection
+                                .send_delivered(signal)
+                                .await
+                                .map_err(|_| NotifyError::SendFailed);
+                            pending.complete(result);
                         }
-                    });
-                    let mut event_sender = characteristic
+                    }));
+                    Ok(())
+                }
+                .map(move |result| ctx.reply(result))
+            });
+            let stop_subscription = Arc::clone(&subscription);
+            b.method_with_cr_async("StopNotify", (), (), move |mut ctx, cr, ()| {
+                let characteristic = GattDataType::characteristic(cr, ctx.path());
+                if let Some(notifier) = stop_subscription.lock_or_recover().take() {
+                    notifier.close();
+                }
+                async move {
+                    let characteristic = characteristic?;
+                    let event_sender = characteristic
                         .properties
                         .notify
                         .clone()
                         .or_else(|| characteristic.properties.indicate.clone())
                         .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
-                    event_sender
-                        .send(gatt::event::Event::NotifySubscribe(notify_subscribe))
-                        .await
-                        .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))
-                        .map(|_| ())
+                    let event_sender = handler::route(&characteristic.handler, event_sender);
+                    request::deliver(event_sender, gatt::event::Event::NotifyUnsubscribe).await
                 }
                 .map(move |result| ctx.reply(result))
             });
-            b.method_with_cr_async("StopNotify", (), (), |mut ctx, cr, ()| {
-                let characteristic = cr
-                    .data_mut::<GattDataType>(ctx.path())
-                    .unwrap()
-                    .get_characteristic();
+            b.method_with_cr_async("Confirm", (), (), move |mut ctx, cr, ()| {
+                let characteristic = GattDataType::characteristic(cr, ctx.path());
                 async move {
-                    let mut event_sender = characteristic
+                    let characteristic = characteristic?;
+                    let event_sender = characteristic
                         .properties
-                        .notify
+                        .indicate
                         .clone()
-                        .or_else(|| characteristic.properties.indicate.clone())
                         .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
-                    event_sender
-                        .send(gatt::event::Event::NotifyUnsubscribe)
-                        .await
-                        .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))
-                        .map(|_| ())
+                    let event_sender = handler::route(&characteristic.handler, event_sender);
+                    request::deliver(event_sender, gatt::event::Event::IndicationConfirmed).await
                 }
                 .map(move |result| ctx.reply(result))
             });
-            b.property("UUID")
-                .get(|_ctx, data| Ok(data.get_characteristic().uuid.to_string()));
+            b.property("UUID").get(|_ctx, data| {
+                data.get_characteristic()
+                    .map(|characteristic| characteristic.uuid.to_string())
+                    .ok_or_else(|| MethodErr::no_property(&"UUID"))
+            });
             let service = service.clone();
             b.property("Service")
                 .get(move |_ctx, _data| Ok(service.clone()));
-            b.property("Flags")
-                .get(move |_ctx, data| Ok(data.get_characteristic().properties.flags()));
+            b.property("Flags").get(move |_ctx, data| {
+                data.get_characteristic()
+                    .map(|characteristic| characteristic.properties.flags())
+                    .ok_or_else(|| MethodErr::no_property(&"Flags"))
+            });
         });
 
         tree.insert(object_path.clone(), &[iface_token], object_path_data);
@@ -212,3 +208,23 @@ impl Characteristic {
         Ok(Characteristic { object_path })
     }
 }
+
+fn value_changed_signal(object_path: &Path<'static>, value: Vec<u8>) -> Message {
+    // For notifications, BlueZ wants a PropertiesChanged signal on the
+    // optional `Value` property. It doesn't require that the property
+    // actually exists.
+    let mut props = HashMap::new();
+    props.insert("Value".to_owned(), Variant(Box::new(value) as _));
+    let signal = PropertiesPropertiesChanged {
+        interface_name: GATT_CHARACTERISTIC_IFACE.to_string(),
+        changed_properties: props,
+        invalidated_properties: Vec::new(),
+    };
+    let mut signal_message = Message::signal(
+        object_path,
+        &DBUS_PROPERTIES_IFACE.into(),
+        &"PropertiesChanged".into(),
+    );
+    signal_message.append_all(signal);
+    signal_message
+}
@@ -1,22 +1,22 @@
-use dbus::{
-    arg::{RefArg, Variant},
-    Path,
-};
+use dbus::Path;
 use dbus_crossroads::MethodErr;
-use futures::{channel::oneshot, prelude::*};
-use std::{collections::HashMap, sync::Arc};
+use futures::prelude::*;
+use std::sync::Arc;
 
 use super::{
     super::{
-        common,
-        common::GattDataType,
-        constants::{BLUEZ_ERROR_FAILED, BLUEZ_ERROR_NOTSUPPORTED, GATT_DESCRIPTOR_IFACE},
+        super::event::EventDispatcher,
+        common::{self, GattDataType},
+        constants::{BLUEZ_ERROR_NOTSUPPORTED, GATT_DESCRIPTOR_IFACE},
     },
     flags::Flags,
+    long_value::LongValueState,
+    request::{Attribute, OptionsMap, RequestOptions},
+};
+use crate::{
+    gatt::{self, handler},
+    Error,
 };
-use crate::{gatt, Error};
-
-type OptionsMap = HashMap<String, Variant<Box<dyn RefArg>>>;
 
 #[derive(Debug, Clone)]
 pub struct Descriptor {
@@ -29,93 +29,79 @@ impl Descriptor {
         descriptor: &Arc<gatt::descriptor::Descriptor>,
         characteristic: &Path<'static>,
         index: u64,
+        events: &EventDispatcher,
     ) -> Result<Self, Error> {
+        let attribute = Attribute {
+            uuid: descriptor.uuid,
+            response_timeout: descriptor.response_timeout.clone(),
+            long_values: descriptor
+                .long_values
+                .map(|long_values| Arc::new(LongValueState::new(long_values))),
+            events: events.clone(),
+        };
         // Setup value property for read / write by other methods
-        let iface_token = tree.register::<GattDataType, _, _>(GATT_DESCRIPTOR_IFACE, |b| {
-            b.method_with_cr_async(
-                "ReadValue",
-                ("options",),
-                ("value",),
-                |mut ctx, cr, (options,): (OptionsMap,)| {
-                    let offset = options.get("offset").and_then(RefArg::as_u64).unwrap_or(0) as u16;
-                    let descriptor = cr
-                        .data_mut::<GattDataType>(ctx.path())
-                        .unwrap()
-                        .get_descriptor();
-                    async move {
-                        let event_sender = descriptor
-                            .properties
-                            .read
-                            .clone()
-                            .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
-                        let (sender, receiver) = oneshot::channel();
-                        event_sender
-                            .sender()
-                            .send(gatt::event::Event::ReadRequest(gatt::event::ReadRequest {
-                                offset,
-                                response: sender,
-                            }))
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))?;
-                        receiver
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))
-                            .and_then(|resp| match resp {
-                                gatt::event::Response::Success(value) => Ok((value,)),
-                                _ => Err(MethodErr::from((BLUEZ_ERROR_FAILED, ""))),
-                            })
-                    }
-                    .map(move |result| ctx.reply(result))
-                },
-            );
-            b.method_with_cr_async(
-                "WriteValue",
-                ("data", "options"),
-                ("value",),
-                |mut ctx, cr, (data, options): (Vec<u8>, OptionsMap)| {
-                    let offset = options.get("offset").and_then(RefArg::as_u64).unwrap_or(0) as u16;
-                    let descriptor = cr
-                        .data_mut::<GattDataType>(ctx.path())
-                        .unwrap()
-                        .get_descriptor();
-                    async move {
-                        let event_sender = descriptor
-                            .properties
-                            .write
-                            .clone()
-                            .ok_or_else(|| MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, "")))?;
-                        let (sender, receiver) = oneshot::channel();
-                        event_sender
-                            .sender()
-                            .send(gatt::event::Event::WriteRequest(
-                                gatt::event::WriteRequest {
-                                    data,
-                                    offset,
-                                    without_response: false,
-                                    response: sender,
-                                },
-                            ))
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))?;
-                        receiver
-                            .await
-                            .map_err(|_| MethodErr::from((BLUEZ_ERROR_FAILED, "")))
-                            .and_then(|resp| match resp {
-                                gatt::event::Response::Success(value) => Ok((value,)),
-                                _ => Err(MethodErr::from((BLUEZ_ERROR_FAILED, ""))),
-                            })
-                    }
-                    .map(move |result| ctx.reply(result))
-                },
-            );
-            b.property("UUID")
-                .get(|_ctx, data| Ok(data.get_descriptor().uuid.to_string()));
-            let characteristic = characteristic.clone();
-            b.property("Characteristic")
-                .get(move |_ctx, _data| Ok(characteristic.clone()));
-            b.property("Flags")
-                .get(move |_ctx, data| Ok(data.get_descriptor().properties.flags()));
-        });
+        let iface_token =
+            tree.register::<GattDataType, _, _>(GATT_DESCRIPTOR_IFACE, |b| {
+                let read_attribute = attribute.clone();
+                let write_attribute = attribute;
+                b.method_with_cr_async(
+                    "ReadValue",
+                    ("options",),
+                    ("value",),
+                    move |mut ctx, cr, (options,): (OptionsMap,)| {
+                        let options = RequestOptions::parse(&options);
+                        let descriptor = GattDataType::descriptor(cr, ctx.path());
+                        let attribute = read_attribute.clone();
+                        async move {
+                            let descriptor = descriptor?;
+                            let event_sender =
+                                descriptor.properties.read.clone().ok_or_else(|| {
+                                    MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, ""))
+                                })?;
+                            let event_sender =
+                                handler::route(&descriptor.handler, event_sender.sender());
+                            let value = attribute.read(event_sender, options).await?;
+                            Ok((value,))
+                        }
+                        .map(move |result| ctx.reply(result))
+                    },
+                );
+                b.method_with_cr_async(
+                    "WriteValue",
+                    ("data", "options"),
+                    ("value",),
+                    move |mut ctx, cr, (data, options): (Vec<u8>, OptionsMap)| {
+                        let options = RequestOptions::parse(&options);
+                        let descriptor = GattDataType::descriptor(cr, ctx.path());
+                        let attribute = write_attribute.clone();
+                        async move {
+                            let descriptor = descriptor?;
+                            let event_sender =
+                                descriptor.properties.write.clone().ok_or_else(|| {
+                                    MethodErr::from((BLUEZ_ERROR_NOTSUPPORTED, ""))
+                                })?;
+                            let event_sender =
+                                handler::route(&descriptor.handler, event_sender.sender());
+                            let value = attribute.write(event_sender, data, options).await?;
+                            Ok((value,))
+                        }
+                        .map(move |result| ctx.reply(result))
+                    },
+                );
+                b.property("UUID").get(|_ctx, data| {
+                    data.get_descriptor()
+                        .map(|descriptor| descriptor.uuid.to_string())
+                        .ok_or_else(|| MethodErr::no_property(&"UUID"))
+                });
+                let characteristic = characteristic.clone();
+                b.property("Characteristic")
+                    .get(move |_ctx, _data| Ok(characteristic.clone()));
+                b.property("Flags").get(move |_ctx, data| {
+                    data.get_descriptor()
+                        .map(|descriptor| descriptor.properties.flags())
+                        .ok_or_else(|| MethodErr::no_property(&"Flags"))
+                });
+            });
         let object_path: Path =
             format!("{}/descriptor{:04}", characteristic.to_string(), index).into();
         let object_path_data = common::GattDataType::Descriptor(Arc::clone(descriptor));
@@ -0,0 +1,249 @@
+use dbus_crossroads::MethodErr;
+use futures::lock::Mutex as AsyncMutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use super::request::{self, Attribute, Request, RequestOptions};
+use crate::{
+    gatt::event::{Event, EventSender, LongValues, ReadRequest, Response, WriteRequest},
+    lock::LockExt,
+    peripheral::PeripheralEvent,
+};
+
+#[derive(Debug, Default)]
+struct PendingWrite {
+    value: Vec<u8>,
+    // New with every part, so a flush can tell whether more came in while it waited
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct CachedRead {
+    value: Vec<u8>,
+    // New with every part, so an expiry can tell whether the client went on reading
+    generation: u64,
+}
+
+// Full values of long reads in progress and parts of long writes, by client
+#[derive(Debug)]
+pub struct LongValueState {
+    config: LongValues,
+    reads: Mutex<HashMap<String, CachedRead>>,
+    // Reads and writes both take their generations from here, so an entry made again after
+    // being dropped can't be taken for the old one
+    generation: AtomicU64,
+    writes: Mutex<HashMap<String, PendingWrite>>,
+    // Held while a request is with the handler, so a pending long write can be handed over
+    // before anything that comes after it
+    order: AsyncMutex<()>,
+}
+
+impl LongValueState {
+    pub fn new(config: LongValues) -> Self {
+        LongValueState {
+            config,
+            reads: Mutex::default(),
+            generation: AtomicU64::new(0),
+            writes: Mutex::default(),
+            order: AsyncMutex::new(()),
+        }
+    }
+
+    pub async fn read(
+        &self,
+        attribute: &Attribute,
+        event_sender: EventSender,
+        options: RequestOptions,
+    ) -> Result<Vec<u8>, MethodErr> {
+        let _order = self.order.lock().await;
+        self.flush_write(attribute, event_sender.clone(), &options.device, None)
+            .await;
+        let cached = match options.offset {
+            0 => None,
+            _ => self
+                .reads
+                .lock_or_recover()
+                .get(&options.device)
+                .map(|cached| cached.value.clone()),
+        };
+        let value = match cached {
+            Some(value) => value,
+            None => {
+                attribute
+                    .request(event_sender, Request::Read, |response| {
+                        Event::ReadRequest(ReadRequest {
+                            offset: 0,
+                            response,
+                        })
+                    })
+                    .await?
+            }
+        };
+
+        let offset = options.offset as usize;
+        if offset > value.len() {
+            self.reads.lock_or_recover().remove(&options.device);
+            return request::method_reply(Response::InvalidOffset);
+        }
+        let part = value[offset..].to_vec();
+        // Without a device there's no telling clients apart, so every part is read afresh
+        if options.device.is_empty() {
+            return Ok(part);
+        }
+        // A read response shorter than the MTU allows is the last one the client asks for
+        if let Some(mtu) = options.mtu {
+            if part.len() < (mtu as usize).saturating_sub(1) {
+                self.reads.lock_or_recover().remove(&options.device);
+                return Ok(part);
+            }
+        }
+
+        // Clients don't say when they're done, e.g. without an MTU or when the value fills the
+        // last part exactly, so the value goes once no more parts have been asked for a while
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst);
+        self.reads
+            .lock_or_recover()
+            .insert(options.device.clone(), CachedRead { value, generation });
+        let attribute = attribute.clone();
+        let settle = self.config.settle;
+        let device = options.device;
+        tokio::spawn(async move {
+            tokio::time::delay_for(settle).await;
+            if let Some(state) = &attribute.long_values {
+                let mut reads = state.reads.lock_or_recover();
+                if reads.get(&device).map(|cached| cached.generation) == Some(generation) {
+                    reads.remove(&device);
+                }
+            }
+        });
+        Ok(part)
+    }
+
+    pub async fn write(
+        &self,
+        attribute: &Attribute,
+        event_sender: EventSender,
+        data: Vec<u8>,
+        options: RequestOptions,
+    ) -> Result<Vec<u8>, MethodErr> {
+        let _order = self.order.lock().await;
+        if options.offset == 0 && !options.reliable {
+            if data.len() > self.config.max_length {
+                return request::method_reply(Response::InvalidAttributeLength);
+            }
+            self.flush_write(attribute, event_sender.clone(), &options.device, None)
+                .await;
+            return self
+                .deliver_write(attribute, event_sender, data, options.command)
+                .await;
+        }
+        // Without a device the parts of different clients can't be told apart
+        if options.device.is_empty() {
+            log::warn!(
+                "Long write to {} from an unknown client was refused",
+                attribute.uuid
+            );
+            return request::method_reply(Response::UnlikelyError);
+        }
+
+        let generation = {
+            let mut writes = self.writes.lock_or_recover();
+            let pending = writes.entry(options.device.clone()).or_default();
+            let offset = options.offset as usize;
+            if offset == 0 {
+                pending.value.clear();
+            }
+            if offset > pending.value.len() {
+                writes.remove(&options.device);
+                return request::method_reply(Response::InvalidOffset);
+            }
+            let end = offset + data.len();
+            if end > self.config.max_length {
+                writes.remove(&options.device);
+                return request::method_reply(Response::InvalidAttributeLength);
+            }
+            if end > pending.value.len() {
+                pending.value.resize(end, 0);
+            }
+            pending.value[offset..end].copy_from_slice(&data);
+            pending.generation = self.generation.fetch_add(1, Ordering::SeqCst);
+            pending.generation
+        };
+
+        // BlueZ doesn't say which part is the last one, so the value is handed over once no
+        // more parts have come in for a while, or before the client's next request
+        let attribute = attribute.clone();
+        let settle = self.config.settle;
+        let device = options.device;
+        tokio::spawn(async move {
+            tokio::time::delay_for(settle).await;
+            if let Some(state) = &attribute.long_values {
+                let _order = state.order.lock().await;
+                state
+                    .flush_write(&attribute, event_sender, &device, Some(generation))
+                    .await;
+            }
+        });
+        Ok(vec![])
+    }
+
+    // Hands over the long write `device` has pending, if it's still at `generation` when given
+    async fn flush_write(
+        &self,
+        attribute: &Attribute,
+        event_sender: EventSender,
+        device: &str,
+        generation: Option<u64>,
+    ) {
+        let value = {
+            let mut writes = self.writes.lock_or_recover();
+            match writes.get(device) {
+                Some(pending) if generation.is_none() || generation == Some(pending.generation) => {
+                    writes.remove(device).map(|pending| pending.value)
+                }
+                _ => None,
+            }
+        };
+        if let Some(value) = value {
+            if self
+                .deliver_write(attribute, event_sender, value, false)
+                .await
+                .is_err()
+            {
+                log::warn!("Long write to {} was not accepted", attribute.uuid);
+                attribute.events.emit(PeripheralEvent::LongWriteFailed {
+                    uuid: attribute.uuid,
+                });
+            }
+        }
+    }
+
+    async fn deliver_write(
+        &self,
+        attribute: &Attribute,
+        event_sender: EventSender,
+        data: Vec<u8>,
+        without_response: bool,
+    ) -> Result<Vec<u8>, MethodErr> {
+        let result = attribute
+            .request(event_sender, Request::Write, |response| {
+                Event::WriteRequest(WriteRequest {
+                    data,
+                    offset: 0,
+                    without_response,
+                    response,
+                })
+            })
+            .await;
+        // Long reads in progress would go on with the old value
+        if result.is_ok() {
+            self.reads.lock_or_recover().clear();
+        }
+        result
+    }
+}
@@ -2,58 +2,76 @@ mod application;
 mod characteristic;
 mod descriptor;
 mod flags;
+mod long_value;
+mod request;
 mod service;
 
-use dbus::{channel::MatchingReceiver, message::MatchRule, Path};
+use dbus::Path;
 use std::sync::{Arc, Mutex};
 
 use self::{
     application::Application, characteristic::Characteristic, descriptor::Descriptor,
     service::Service,
 };
-use super::{common, constants::PATH_BASE, Connection};
-use crate::{gatt, Error};
+use super::{super::event::EventDispatcher, common, connection::Route, Connection};
+use crate::{gatt, lock::LockExt, Error, ErrorKind, ErrorType};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Gatt {
     connection: Arc<Connection>,
     adapter: Path<'static>,
-    tree: Arc<Mutex<Option<common::Tree>>>,
+    object_path: Path<'static>,
+    tree: Arc<Mutex<common::Tree>>,
     application: Arc<Mutex<Option<Application>>>,
+    route: Arc<Mutex<Option<Route>>>,
     service_index: Arc<Mutex<u64>>,
     characteristic_index: Arc<Mutex<u64>>,
     descriptor_index: Arc<Mutex<u64>>,
+    events: EventDispatcher,
 }
 
 impl Gatt {
-    pub fn new(connection: Arc<Connection>, adapter: Path<'static>) -> Self {
+    pub fn new(
+        connection: Arc<Connection>,
+        adapter: Path<'static>,
+        namespace: &Path,
+        events: EventDispatcher,
+    ) -> Self {
         let mut tree = common::Tree::new();
         tree.set_async_support(Some((
-            connection.default.clone(),
+            connection.clone(),
             Box::new(|x| {
                 tokio::spawn(x);
             }),
         )));
         Gatt {
             adapter,
+            object_path: format!("{}/gatt", namespace).into(),
             connection,
-            tree: Arc::new(Mutex::new(Some(tree))),
+            tree: Arc::new(Mutex::new(tree)),
             application: Arc::new(Mutex::new(None)),
+            route: Arc::new(Mutex::new(None)),
             service_index: Arc::new(Mutex::new(0)),
             characteristic_index: Arc::new(Mutex::new(0)),
             descriptor_index: Arc::new(Mutex::new(0)),
+            events,
         }
     }
 
     pub fn add_service(self: &Self, service: &gatt::service::Service) -> Result<(), Error> {
-        let mut tree = self.tree.lock().unwrap();
-        let tree = tree.as_mut().unwrap();
-
-        let mut service_index = self.service_index.lock().unwrap();
-        let mut characteristic_index = self.characteristic_index.lock().unwrap();
-        let mut descriptor_index = self.descriptor_index.lock().unwrap();
-
-        let gatt_service = Service::new(tree, &Arc::new(service.clone()), *service_index)?;
+        let mut tree = self.tree.lock_or_recover();
+        let tree = &mut *tree;
+
+        let mut service_index = self.service_index.lock_or_recover();
+        let mut characteristic_index = self.characteristic_index.lock_or_recover();
+        let mut descriptor_index = self.descriptor_index.lock_or_recover();
+
+        let gatt_service = Service::new(
+            tree,
+            &Arc::new(service.clone()),
+            &self.object_path,
+            *service_index,
+        )?;
         *service_index += 1;
 
         for characteristic in service.characteristics.iter() {
@@ -63,6 +81,7 @@ impl Gatt {
                 &Arc::new(characteristic.clone()),
                 &Arc::new(gatt_service.object_path.clone()),
                 *characteristic_index,
+                &self.events,
             )?;
             *characteristic_index += 1;
 
@@ -72,6 +91,7 @@ impl Gatt {
                     &Arc::new(descriptor.clone()),
                     &Arc::new(gatt_characteristic.object_path.clone()),
                     *descriptor_index,
+                    &self.events,
                 )?;
                 *descriptor_index += 1;
             }
@@ -81,41 +101,71 @@ impl Gatt {
     }
 
     pub async fn register(self: &Self) -> Result<(), Error> {
-        let mut tree = self.tree.lock().unwrap().take().unwrap();
-
-        let new_application = Application::new(
-            Arc::clone(&self.connection),
-            &mut tree,
-            self.adapter.clone(),
-        );
-
-        self.application
-            .lock()
-            .unwrap()
-            .replace(new_application.clone());
-
-        let mut match_rule = MatchRule::new_method_call();
-        match_rule.path = Some(PATH_BASE.into());
-        match_rule.path_is_namespace = true;
-        self.connection.default.start_receive(
-            match_rule,
-            Box::new(move |msg, conn| {
-                tree.handle_message(msg, conn).unwrap();
-                true
-            }),
-        );
+        let new_application = {
+            let mut application = self.application.lock_or_recover();
+            if application.is_some() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyRegistered,
+                    "The GATT application is already registered",
+                    ErrorType::Bluez,
+                ));
+            }
+            let new_application = Application::new(
+                Arc::clone(&self.connection),
+                &mut self.tree.lock_or_recover(),
+                self.adapter.clone(),
+                self.object_path.clone(),
+            );
+            application.replace(new_application.clone());
+            new_application
+        };
+
+        self.route.lock_or_recover().replace(self.connection.route(
+            self.object_path.clone(),
+            true,
+            self.tree.clone(),
+        ));
+
+        let result = new_application.register().await;
+        if result.is_err() {
+            self.application.lock_or_recover().take();
+            self.route.lock_or_recover().take();
+        }
+        result
+    }
 
-        new_application.register().await
+    // Registers the application again if it was registered when BlueZ lost track of it
+    pub async fn restore(&self) -> Result<(), Error> {
+        let application = self.application.lock_or_recover().clone();
+        match application {
+            Some(application) => application.register().await,
+            None => Ok(()),
+        }
     }
 
     pub async fn unregister(self: &Self) -> Result<(), Error> {
-        self.application
-            .lock()
-            .unwrap()
-            .take()
-            .unwrap()
-            .unregister()
-            .await
-            .map(|_| ())
+        let application = self.application.lock_or_recover().take();
+        let application = application.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotRegistered,
+                "The GATT application is not registered",
+                ErrorType::Bluez,
+            )
+        })?;
+        let result = application.unregister().await;
+        self.route.lock_or_recover().take();
+        result
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.application.lock_or_recover().is_some()
+    }
+
+    // Best effort unregistration for when there is no way to wait for BlueZ
+    pub fn release(&self) {
+        if let Some(application) = self.application.lock_or_recover().take() {
+            application.release();
+        }
+        self.route.lock_or_recover().take();
     }
 }
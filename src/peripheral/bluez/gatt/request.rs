@@ -0,0 +1,190 @@
+use dbus::arg::{RefArg, Variant};
+use dbus_crossroads::MethodErr;
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    prelude::*,
+};
+use std::{collections::HashMap, fmt, sync::Arc};
+use uuid::Uuid;
+
+use super::{
+    super::{
+        super::event::{EventDispatcher, PeripheralEvent},
+        constants::{
+            BLUEZ_ERROR_FAILED, BLUEZ_ERROR_INPROGRESS, BLUEZ_ERROR_INVALIDOFFSET,
+            BLUEZ_ERROR_INVALIDVALUELENGTH,
+        },
+    },
+    long_value::LongValueState,
+};
+use crate::gatt::event::{
+    Delivery, Event, EventSender, ReadRequest, Response, ResponseSender, ResponseTimeout,
+    WriteRequest,
+};
+
+pub type OptionsMap = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Request {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Read => write!(f, "Read"),
+            Request::Write => write!(f, "Write"),
+        }
+    }
+}
+
+// The options of ReadValue and WriteValue this crate looks at
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    pub offset: u16,
+    // Object path of the client, empty if BlueZ didn't say
+    pub device: String,
+    pub mtu: Option<u16>,
+    // Part of a long write being executed
+    pub reliable: bool,
+    // Write command, the client doesn't wait for the reply
+    pub command: bool,
+}
+
+impl RequestOptions {
+    pub fn parse(options: &OptionsMap) -> Self {
+        let write_type = options.get("type").and_then(RefArg::as_str);
+        RequestOptions {
+            offset: options.get("offset").and_then(RefArg::as_u64).unwrap_or(0) as u16,
+            device: options
+                .get("device")
+                .and_then(RefArg::as_str)
+                .unwrap_or_default()
+                .to_owned(),
+            mtu: options
+                .get("mtu")
+                .and_then(RefArg::as_u64)
+                .map(|mtu| mtu as u16),
+            reliable: write_type == Some("reliable"),
+            command: write_type == Some("command"),
+        }
+    }
+}
+
+// What a characteristic or descriptor needs to pass requests on to its handler
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub uuid: Uuid,
+    pub response_timeout: Option<ResponseTimeout>,
+    pub long_values: Option<Arc<LongValueState>>,
+    pub events: EventDispatcher,
+}
+
+impl Attribute {
+    pub async fn read(
+        &self,
+        event_sender: EventSender,
+        options: RequestOptions,
+    ) -> Result<Vec<u8>, MethodErr> {
+        if let Some(long_values) = &self.long_values {
+            return long_values.read(self, event_sender, options).await;
+        }
+        self.request(event_sender, Request::Read, |response| {
+            Event::ReadRequest(ReadRequest {
+                offset: options.offset,
+                response,
+            })
+        })
+        .await
+    }
+
+    pub async fn write(
+        &self,
+        event_sender: EventSender,
+        data: Vec<u8>,
+        options: RequestOptions,
+    ) -> Result<Vec<u8>, MethodErr> {
+        if let Some(long_values) = &self.long_values {
+            return long_values.write(self, event_sender, data, options).await;
+        }
+        self.request(event_sender, Request::Write, |response| {
+            Event::WriteRequest(WriteRequest {
+                data,
+                offset: options.offset,
+                without_response: options.command,
+                response,
+            })
+        })
+        .await
+    }
+
+    // Hands the request to the handler and turns its response into the method reply. If the
+    // handler doesn't answer in time, the timeout's response is sent on its behalf instead.
+    pub async fn request<F>(
+        &self,
+        event_sender: EventSender,
+        request: Request,
+        event: F,
+    ) -> Result<Vec<u8>, MethodErr>
+    where
+        F: FnOnce(ResponseSender) -> Event,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let event = event(sender);
+        let exchange = async move {
+            match event_sender.deliver(event).await {
+                Ok(()) => receiver.await.map_err(|_| failed()),
+                Err(Delivery::Rejected(response)) => Ok(response),
+                Err(Delivery::Dropped) => Err(failed()),
+            }
+        };
+
+        let timeout = match &self.response_timeout {
+            Some(timeout) => timeout,
+            None => return method_reply(exchange.await?),
+        };
+        match future::select(exchange.boxed(), tokio::time::delay_for(timeout.duration)).await {
+            Either::Left((response, _)) => method_reply(response?),
+            Either::Right(_) => {
+                log::warn!(
+                    "{} handler for {} did not respond within {:?}",
+                    request,
+                    self.uuid,
+                    timeout.duration
+                );
+                self.events.emit(match request {
+                    Request::Read => PeripheralEvent::ReadTimedOut { uuid: self.uuid },
+                    Request::Write => PeripheralEvent::WriteTimedOut { uuid: self.uuid },
+                });
+                method_reply(timeout.response.clone())
+            }
+        }
+    }
+}
+
+// Passes on an event that isn't answered by the handler
+pub async fn deliver(event_sender: EventSender, event: Event) -> Result<(), MethodErr> {
+    match event_sender.deliver(event).await {
+        Ok(()) => Ok(()),
+        Err(Delivery::Rejected(response)) => method_reply(response).map(|_| ()),
+        Err(Delivery::Dropped) => Err(failed()),
+    }
+}
+
+pub fn method_reply(response: Response) -> Result<Vec<u8>, MethodErr> {
+    match response {
+        Response::Success(value) => Ok(value),
+        Response::InvalidOffset => Err(MethodErr::from((BLUEZ_ERROR_INVALIDOFFSET, ""))),
+        Response::InvalidAttributeLength => {
+            Err(MethodErr::from((BLUEZ_ERROR_INVALIDVALUELENGTH, "")))
+        }
+        Response::UnlikelyError => Err(failed()),
+        Response::Busy => Err(MethodErr::from((BLUEZ_ERROR_INPROGRESS, ""))),
+    }
+}
+
+fn failed() -> MethodErr {
+    MethodErr::from((BLUEZ_ERROR_FAILED, ""))
+}
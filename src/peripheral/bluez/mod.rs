@@ -1,40 +1,211 @@
 mod adapter;
 mod advertisement;
+mod bus;
 mod common;
 mod connection;
 mod constants;
 mod error;
+#[cfg(feature = "fake-bluez")]
+mod fake;
 mod gatt;
 
-use std::{string::ToString, sync::Arc};
-use uuid::Uuid;
+use dbus::Path;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    prelude::*,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use self::{adapter::Adapter, advertisement::Advertisement, connection::Connection, gatt::Gatt};
-use crate::{gatt::service::Service, Error};
+#[cfg(feature = "fake-bluez")]
+pub use self::fake::{FakeBluez, FakeRegistration};
+use self::{
+    adapter::Adapter,
+    advertisement::{Instances, WeakAdvertisement},
+    connection::{Connection, ConnectionEvent},
+    constants::PATH_BASE,
+    gatt::Gatt,
+};
+pub use self::{
+    advertisement::Advertisement,
+    bus::{BusAddress, BusConfig},
+};
+use super::event::{EventDispatcher, PeripheralEvent};
+use crate::{
+    advertisement::{
+        capabilities::AdvertisingCapabilities, data::AdvertisementData, eddystone::EddystoneTlm,
+        ibeacon::IBeacon,
+    },
+    gatt::service::Service,
+    lock::LockExt,
+    Error, ErrorKind, ErrorType,
+};
+
+const RESTORE_ATTEMPTS: usize = 5;
+const RESTORE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Namespaces in use by the peripherals sharing a connection
+type Namespaces = Arc<Mutex<BTreeSet<String>>>;
+
+type Advertisements = Arc<Mutex<Vec<WeakAdvertisement>>>;
+
+// Advertisement instances in use on each adapter, by the peripherals sharing a connection
+type AdapterInstances = Arc<Mutex<HashMap<Path<'static>, Instances>>>;
 
 #[derive(Debug)]
 pub struct Peripheral {
+    connection: Arc<Connection>,
     adapter: Adapter,
+    namespace: Path<'static>,
+    namespaces: Namespaces,
     gatt: Gatt,
-    advertisement: Advertisement,
+    adapter_instances: AdapterInstances,
+    advertisement_instances: Instances,
+    advertisements: Advertisements,
+    advertisement: Mutex<Option<Advertisement>>,
+    events: EventDispatcher,
+    // Dropping this stops the connection watcher
+    _shutdown: oneshot::Sender<()>,
 }
 
 impl Peripheral {
     #[allow(clippy::new_ret_no_self)]
     pub async fn new() -> Result<Self, Error> {
-        let connection = Arc::new(Connection::new()?);
-        let adapter = Adapter::new(connection.clone()).await?;
+        Peripheral::with_namespace(&generated_namespace()).await
+    }
+
+    // All objects exported for this peripheral are placed under `namespace`, e.g. `/com/example/sensor`
+    pub async fn with_namespace(namespace: &str) -> Result<Self, Error> {
+        Peripheral::with_bus(BusConfig::default(), Some(namespace), None).await
+    }
+
+    // Talks to BlueZ on the given bus and under the given service name instead of `org.bluez`
+    // on the system bus. `adapter` picks one by name, e.g. `hci1`, or object path; otherwise the
+    // first adapter found is used.
+    pub async fn with_bus(
+        bus: BusConfig,
+        namespace: Option<&str>,
+        adapter: Option<&str>,
+    ) -> Result<Self, Error> {
+        let namespace = match namespace {
+            Some(namespace) => parse_namespace(namespace)?,
+            None => parse_namespace(&generated_namespace())?,
+        };
+        let connection = Connection::new(bus)?;
+        let adapter = Adapter::new(connection.clone(), adapter).await?;
         adapter.powered(true).await?;
-        let gatt = Gatt::new(connection.clone(), adapter.object_path.clone());
-        let advertisement = Advertisement::new(connection, adapter.object_path.clone());
+        Peripheral::build(
+            connection,
+            adapter,
+            namespace,
+            Namespaces::default(),
+            AdapterInstances::default(),
+        )
+    }
+
+    // Creates another peripheral on this one's D-Bus connection. It gets its own namespace, GATT
+    // application and events, and uses `adapter` if given or else this one's, sharing the
+    // advertisement instances of that adapter with other peripherals on the connection.
+    pub async fn new_sharing_connection(
+        &self,
+        namespace: Option<&str>,
+        adapter: Option<&str>,
+    ) -> Result<Self, Error> {
+        let namespace = match namespace {
+            Some(namespace) => parse_namespace(namespace)?,
+            None => parse_namespace(&generated_namespace())?,
+        };
+        let adapter = match adapter {
+            Some(adapter) => {
+                let adapter = Adapter::new(self.connection.clone(), Some(adapter)).await?;
+                adapter.powered(true).await?;
+                adapter
+            }
+            None => self.adapter.clone(),
+        };
+        Peripheral::build(
+            self.connection.clone(),
+            adapter,
+            namespace,
+            self.namespaces.clone(),
+            self.adapter_instances.clone(),
+        )
+    }
+
+    fn build(
+        connection: Arc<Connection>,
+        adapter: Adapter,
+        namespace: Path<'static>,
+        namespaces: Namespaces,
+        adapter_instances: AdapterInstances,
+    ) -> Result<Self, Error> {
+        {
+            let mut namespaces = namespaces.lock_or_recover();
+            // Overlapping namespaces would route one peripheral's calls to the other
+            let overlaps = |a: &str, b: &str| a == b || a.starts_with(&format!("{}/", b));
+            if let Some(used) = namespaces
+                .iter()
+                .find(|used| overlaps(&namespace, used) || overlaps(used, &namespace))
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidDefinition,
+                    format!("{} overlaps {} of another peripheral", namespace, used),
+                    ErrorType::Bluez,
+                ));
+            }
+            namespaces.insert(namespace.to_string());
+        }
+
+        let advertisement_instances = adapter_instances
+            .lock_or_recover()
+            .entry(adapter.object_path.clone())
+            .or_default()
+            .clone();
+        let events = EventDispatcher::default();
+        let gatt = Gatt::new(
+            connection.clone(),
+            adapter.object_path.clone(),
+            &namespace,
+            events.clone(),
+        );
+        let advertisements = Advertisements::default();
+
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        tokio::spawn(watch_connection(
+            connection.events(),
+            shutdown_receiver,
+            gatt.clone(),
+            advertisements.clone(),
+            events.clone(),
+        ));
 
         Ok(Peripheral {
+            connection,
             adapter,
+            namespace,
+            namespaces,
             gatt,
-            advertisement,
+            adapter_instances,
+            advertisement_instances,
+            advertisements,
+            advertisement: Mutex::new(None),
+            events,
+            _shutdown: shutdown,
         })
     }
 
+    pub fn events(&self) -> mpsc::UnboundedReceiver<PeripheralEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn is_powered(self: &Self) -> Result<bool, Error> {
         self.adapter.is_powered().await
     }
@@ -47,28 +218,286 @@ impl Peripheral {
         self.gatt.unregister().await
     }
 
-    pub async fn start_advertising(self: &Self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
-        self.advertisement.add_name(name);
-        self.advertisement.add_uuids(
-            uuids
-                .to_vec()
-                .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<String>>(),
-        );
+    // Stops all advertisements and unregisters the GATT application, after which BlueZ calls are
+    // no longer handled. Everything is attempted even if a step fails; the first error is returned.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let mut results = vec![];
+        for advertisement in live_advertisements(&self.advertisements) {
+            if advertisement.is_advertising() {
+                results.push(advertisement.stop().await);
+            } else {
+                advertisement.release();
+            }
+        }
+        self.advertisement.lock_or_recover().take();
+
+        if self.gatt.is_registered() {
+            results.push(self.gatt.unregister().await);
+        }
+        results.into_iter().collect()
+    }
 
-        self.advertisement.register().await
+    pub async fn start_advertising(
+        self: &Self,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        self.default_advertisement()
+            .await?
+            .start(advertisement_data)
+            .await
+    }
+
+    pub async fn start_broadcasting(
+        &self,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        self.default_advertisement()
+            .await?
+            .start_broadcast(advertisement_data)
+            .await
+    }
+
+    pub async fn start_ibeacon(&self, ibeacon: &IBeacon) -> Result<(), Error> {
+        self.start_broadcasting(&ibeacon.advertisement_data()).await
+    }
+
+    pub async fn start_eddystone_tlm<F>(
+        &self,
+        interval: Duration,
+        telemetry: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut() -> EddystoneTlm + Send + 'static,
+    {
+        self.default_advertisement()
+            .await?
+            .start_eddystone_tlm(interval, telemetry)
+            .await
+    }
+
+    // Rotates through `payloads`, as connectable advertisements or broadcasts
+    pub async fn start_rotation(
+        &self,
+        payloads: Vec<AdvertisementData>,
+        interval: Duration,
+        connectable: bool,
+    ) -> Result<(), Error> {
+        self.default_advertisement()
+            .await?
+            .start_rotation(payloads, interval, connectable)
+            .await
+    }
+
+    pub async fn update_advertising(
+        &self,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        self.default_advertisement()
+            .await?
+            .update(advertisement_data)
+            .await
     }
 
     pub async fn stop_advertising(self: &Self) -> Result<(), Error> {
-        self.advertisement.unregister().await
+        let advertisement = self.advertisement.lock_or_recover().clone();
+        match advertisement {
+            Some(advertisement) => advertisement.stop().await,
+            None => Ok(()),
+        }
     }
 
     pub async fn is_advertising(self: &Self) -> Result<bool, Error> {
-        Ok(self.advertisement.is_advertising())
+        Ok(self
+            .advertisement
+            .lock_or_recover()
+            .as_ref()
+            .map(Advertisement::is_advertising)
+            .unwrap_or(false))
+    }
+
+    pub async fn advertising_capabilities(&self) -> Result<AdvertisingCapabilities, Error> {
+        self.adapter.advertising_capabilities().await
+    }
+
+    pub async fn new_advertisement(&self) -> Result<Advertisement, Error> {
+        let total_instances = self.adapter.total_advertisement_instances().await?;
+        let advertisement = Advertisement::new(
+            self.connection.clone(),
+            self.adapter.clone(),
+            &self.namespace,
+            &self.advertisement_instances,
+            total_instances,
+            self.events.clone(),
+        )?;
+
+        let mut advertisements = self.advertisements.lock_or_recover();
+        advertisements.retain(|advertisement| advertisement.upgrade().is_some());
+        advertisements.push(advertisement.downgrade());
+        Ok(advertisement)
+    }
+
+    // Off by default; when enabled, a lost D-Bus connection is re-established and the GATT
+    // application and running advertisements are registered again
+    pub fn set_auto_reconnect(&self, auto_reconnect: bool) {
+        self.connection.set_auto_reconnect(auto_reconnect);
+    }
+
+    async fn default_advertisement(&self) -> Result<Advertisement, Error> {
+        let advertisement = self.advertisement.lock_or_recover().clone();
+        match advertisement {
+            Some(advertisement) => Ok(advertisement),
+            None => {
+                let advertisement = self.new_advertisement().await?;
+                Ok(self
+                    .advertisement
+                    .lock_or_recover()
+                    .get_or_insert(advertisement)
+                    .clone())
+            }
+        }
     }
 
     pub fn add_service(self: &Self, service: &Service) -> Result<(), Error> {
         self.gatt.add_service(service)
     }
 }
+
+impl Drop for Peripheral {
+    // Nothing can be awaited here, so BlueZ is asked to unregister without waiting for its
+    // replies. Call `shutdown` first to find out whether that worked.
+    fn drop(&mut self) {
+        for advertisement in live_advertisements(&self.advertisements) {
+            advertisement.release();
+        }
+        self.gatt.release();
+        self.namespaces.lock_or_recover().remove(&*self.namespace);
+    }
+}
+
+async fn watch_connection(
+    mut connection_events: mpsc::UnboundedReceiver<ConnectionEvent>,
+    mut shutdown: oneshot::Receiver<()>,
+    gatt: Gatt,
+    advertisements: Advertisements,
+    events: EventDispatcher,
+) {
+    while let Either::Left((Some(event), _)) =
+        future::select(connection_events.next(), &mut shutdown).await
+    {
+        match event {
+            ConnectionEvent::Lost { reason } => {
+                events.emit(PeripheralEvent::ConnectionLost { reason })
+            }
+            ConnectionEvent::Restored => {
+                events.emit(PeripheralEvent::ConnectionRestored);
+                restore(&gatt, &advertisements, &events).await;
+            }
+            ConnectionEvent::BluezStopped => events.emit(PeripheralEvent::BluezStopped),
+            ConnectionEvent::BluezStarted => {
+                events.emit(PeripheralEvent::BluezStarted);
+                restore(&gatt, &advertisements, &events).await;
+            }
+        }
+    }
+}
+
+// BlueZ forgets everything a client registered once the client leaves the bus
+async fn restore(gatt: &Gatt, advertisements: &Advertisements, events: &EventDispatcher) {
+    let mut results = vec![with_retries(|| gatt.restore()).await];
+    for advertisement in live_advertisements(advertisements).iter() {
+        results.push(with_retries(|| advertisement.restore()).await);
+    }
+
+    for err in results.into_iter().filter_map(Result::err) {
+        events.emit(PeripheralEvent::RestoreFailed {
+            reason: err.to_string(),
+        });
+    }
+}
+
+fn live_advertisements(advertisements: &Advertisements) -> Vec<Advertisement> {
+    advertisements
+        .lock_or_recover()
+        .iter()
+        .filter_map(WeakAdvertisement::upgrade)
+        .collect()
+}
+
+// A freshly started bluetoothd owns its name before its adapters are set up, so errors that
+// go away with time are retried. Whatever BlueZ still has registered needs no restoring.
+async fn with_retries<F, T>(mut attempt: F) -> Result<(), Error>
+where
+    F: FnMut() -> T,
+    T: Future<Output = Result<(), Error>>,
+{
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(ref err) if err.kind() == &ErrorKind::AlreadyRegistered => return Ok(()),
+            Err(ref err) if is_transient(err) && attempts < RESTORE_ATTEMPTS => {
+                attempts += 1;
+                tokio::time::delay_for(RESTORE_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::AdapterNotFound
+            | ErrorKind::NotPowered
+            | ErrorKind::Timeout
+            | ErrorKind::BackendDisconnected
+    )
+}
+
+fn generated_namespace() -> String {
+    // Process ID and a per-process counter keep every generated namespace unique on the bus
+    static NAMESPACE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "{}/bluster{}_{}",
+        PATH_BASE,
+        process::id(),
+        NAMESPACE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn parse_namespace(namespace: &str) -> Result<Path<'static>, Error> {
+    Path::new(namespace.to_owned())
+        .and_then(|namespace| match &*namespace {
+            "/" => Err("The root path cannot be used as a namespace".to_owned()),
+            _ => Ok(namespace),
+        })
+        .map_err(|err| Error::new(ErrorKind::InvalidDefinition, err, ErrorType::Bluez))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn attempts_until(kind: ErrorKind) -> (Result<(), Error>, usize) {
+        let attempts = AtomicUsize::new(0);
+        let result = with_retries(|| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            future::err(Error::new(kind.clone(), "", ErrorType::Bluez))
+        })
+        .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_with_retries_accepts_already_registered() {
+        let (result, attempts) = attempts_until(ErrorKind::AlreadyRegistered).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_with_retries_gives_up_on_permanent_errors() {
+        let (result, attempts) = attempts_until(ErrorKind::InvalidDefinition).await;
+        assert_eq!(result.unwrap_err().kind(), &ErrorKind::InvalidDefinition);
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,19 +1,20 @@
 mod characteristic_flags;
 mod constants;
-mod error;
 mod events;
 mod ffi;
 mod into_bool;
 mod into_cbuuid;
 mod peripheral_manager;
 
-use uuid::Uuid;
+use futures::channel::mpsc;
 
 use self::peripheral_manager::PeripheralManager;
-use crate::{gatt::service::Service, Error};
+use super::event::{EventDispatcher, PeripheralEvent};
+use crate::{advertisement::data::AdvertisementData, gatt::service::Service, Error};
 
 pub struct Peripheral {
     peripheral_manager: PeripheralManager,
+    events: EventDispatcher,
 }
 
 impl Peripheral {
@@ -21,9 +22,14 @@ impl Peripheral {
     pub async fn new() -> Result<Self, Error> {
         Ok(Peripheral {
             peripheral_manager: PeripheralManager::new(),
+            events: EventDispatcher::default(),
         })
     }
 
+    pub fn events(&self) -> mpsc::UnboundedReceiver<PeripheralEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn is_powered(&self) -> Result<bool, Error> {
         Ok(self.peripheral_manager.is_powered())
     }
@@ -36,11 +42,40 @@ impl Peripheral {
         Ok(())
     }
 
-    pub async fn start_advertising(self: &Self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
-        self.peripheral_manager.start_advertising(name, uuids);
+    pub async fn start_advertising(
+        self: &Self,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        // CoreBluetooth only lets peripherals advertise a local name and service UUIDs, and
+        // moves whatever doesn't fit into the scan response by itself
+        let scan_response = advertisement_data.scan_response.as_ref();
+        let local_name = advertisement_data
+            .local_name
+            .as_ref()
+            .or_else(|| scan_response.and_then(|scan_response| scan_response.local_name.as_ref()));
+        let mut service_uuids = advertisement_data.service_uuids.clone();
+        if let Some(scan_response) = scan_response {
+            service_uuids.extend(&scan_response.service_uuids);
+        }
+        self.peripheral_manager
+            .start_advertising(local_name.map(String::as_str).unwrap_or(""), &service_uuids);
         Ok(())
     }
 
+    // Advertising data can't change while running, so restart with the new data
+    pub async fn update_advertising(
+        &self,
+        advertisement_data: &AdvertisementData,
+    ) -> Result<(), Error> {
+        self.stop_advertising().await?;
+        self.start_advertising(advertisement_data).await
+    }
+
+    // CoreBluetooth removes services along with the peripheral manager, so only advertising stops
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.stop_advertising().await
+    }
+
     pub async fn stop_advertising(&self) -> Result<(), Error> {
         self.peripheral_manager.stop_advertising();
         Ok(())
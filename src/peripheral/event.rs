@@ -0,0 +1,47 @@
+use futures::channel::mpsc;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::lock::LockExt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeripheralEvent {
+    // The Bluetooth stack stopped an advertisement on its own, e.g. when its
+    // timeout expired
+    AdvertisementReleased { instance: u16 },
+    ConnectionLost { reason: String },
+    // Emitted once the connection is back, before the application and
+    // advertisements are registered again
+    ConnectionRestored,
+    RestoreFailed { reason: String },
+    // bluetoothd went away, taking the application and advertisements with it
+    BluezStopped,
+    // bluetoothd is back, the application and advertisements are about to be registered again
+    BluezStarted,
+    // A handler didn't answer in time, so its characteristic or descriptor's timeout response
+    // was sent instead
+    ReadTimedOut { uuid: Uuid },
+    WriteTimedOut { uuid: Uuid },
+    // The handler refused a long write put back together by `LongValues`, or didn't answer it.
+    // The central was told every part was accepted, so it won't find out.
+    LongWriteFailed { uuid: Uuid },
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventDispatcher {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<PeripheralEvent>>>>,
+}
+
+impl EventDispatcher {
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<PeripheralEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.lock_or_recover().push(sender);
+        receiver
+    }
+
+    pub fn emit(&self, event: PeripheralEvent) {
+        self.subscribers
+            .lock_or_recover()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
@@ -1,3 +1,6 @@
+mod event;
+pub use self::event::PeripheralEvent;
+
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 mod corebluetooth;
 #[cfg(any(target_os = "macos", target_os = "ios"))]
@@ -6,7 +9,12 @@ pub use self::corebluetooth::Peripheral;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod bluez;
 #[cfg(any(target_os = "linux", target_os = "android"))]
-pub use self::bluez::Peripheral;
+pub use self::bluez::{Advertisement, BusAddress, BusConfig, Peripheral};
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    feature = "fake-bluez"
+))]
+pub use self::bluez::{FakeBluez, FakeRegistration};
 
 #[cfg(any(target_os = "windows", target_os = "freebsd"))]
 mod usb;
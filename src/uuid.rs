@@ -10,3 +10,12 @@ pub trait SdpShortUuid<T: Into<u32>> {
 
 impl SdpShortUuid<u16> for Uuid {}
 impl SdpShortUuid<u32> for Uuid {}
+
+pub(crate) fn to_sdp_short_uuid(uuid: &Uuid) -> Option<u32> {
+    let (short, data2, data3, data4) = uuid.as_fields();
+    if (data2, data3, data4) == (BASE_UUID.1, BASE_UUID.2, BASE_UUID.3) {
+        Some(short)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,159 @@
+use bluster::{
+    advertisement::{
+        ad_structure::{self, AdError, AdStructure, ServiceUuids, LEGACY_MAX_LENGTH},
+        data::{AdvertisementData, ScanResponseData},
+        eddystone::{EddystoneError, EddystoneTlm, EddystoneUid, EddystoneUrl},
+        ibeacon::{IBeacon, APPLE_COMPANY_ID},
+    },
+    SdpShortUuid,
+};
+use std::time::Duration;
+use uuid::Uuid;
+
+#[test]
+fn test_encode_decode_roundtrip() {
+    let mut advertisement_data =
+        AdvertisementData::new("bluster", &[Uuid::from_sdp_short_uuid(0x180F_u16)]);
+    advertisement_data
+        .manufacturer_data
+        .insert(0x004C, vec![0x01, 0x02]);
+
+    let structures = advertisement_data.ad_structures();
+    let payload = ad_structure::encode(&structures).unwrap();
+    assert_eq!(
+        payload,
+        vec![
+            0x08, 0x09, b'b', b'l', b'u', b's', b't', b'e', b'r', 0x03, 0x03, 0x0F, 0x18, 0x05,
+            0xFF, 0x4C, 0x00, 0x01, 0x02,
+        ]
+    );
+    assert_eq!(ad_structure::decode(&payload).unwrap(), structures);
+}
+
+#[test]
+fn test_decode_stops_at_zero_length() {
+    let payload = [0x02, 0x01, 0x06, 0x00, 0x00, 0x00];
+    assert_eq!(
+        ad_structure::decode(&payload).unwrap(),
+        vec![AdStructure::Flags(0x06)]
+    );
+}
+
+#[test]
+fn test_decode_uuid128() {
+    let uuid = Uuid::parse_str("6e400001-b5a3-f393-e0a9-e50e24dcca9e").unwrap();
+    let structure = AdStructure::ServiceUuids {
+        uuids: ServiceUuids::Uuids128(vec![uuid]),
+        complete: true,
+    };
+    let payload = ad_structure::encode(std::slice::from_ref(&structure)).unwrap();
+    assert_eq!(&payload[..3], &[0x11, 0x07, 0x9E]);
+    assert_eq!(ad_structure::decode(&payload).unwrap(), vec![structure]);
+}
+
+#[test]
+fn test_decode_truncated() {
+    assert_eq!(
+        ad_structure::decode(&[0x05, 0xFF, 0x4C]),
+        Err(AdError::Truncated { offset: 0 })
+    );
+}
+
+#[test]
+fn test_validate_payload_too_long() {
+    let structures = [
+        AdStructure::Flags(0x06),
+        AdStructure::CompleteLocalName(String::from("a name long enough for the budget")),
+    ];
+    let error = ad_structure::validate(&structures, LEGACY_MAX_LENGTH).unwrap_err();
+    assert_eq!(error.to_string(), "payload 38 bytes > 31");
+}
+
+#[test]
+fn test_ibeacon_manufacturer_data() {
+    let proximity_uuid = Uuid::parse_str("e2c56db5-dffb-48d2-b060-d0f5a71096e0").unwrap();
+    let ibeacon = IBeacon::new(proximity_uuid, 0x0102, 0x0304, -59);
+    let advertisement_data = AdvertisementData::from(ibeacon);
+
+    let mut expected = vec![0x02, 0x15];
+    expected.extend_from_slice(proximity_uuid.as_bytes());
+    expected.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0xC5]);
+    assert_eq!(
+        advertisement_data.manufacturer_data.get(&APPLE_COMPANY_ID),
+        Some(&expected)
+    );
+    assert_eq!(
+        ad_structure::validate(&advertisement_data.ad_structures(), LEGACY_MAX_LENGTH),
+        Ok(27)
+    );
+}
+
+#[test]
+fn test_eddystone_uid_frame() {
+    let uid = EddystoneUid::new(-20, [0x11; 10], [0x22; 6]);
+    let frame = uid.frame();
+    assert_eq!(frame.len(), 20);
+    assert_eq!(&frame[..3], &[0x00, 0xEC, 0x11]);
+    assert_eq!(
+        &frame[12..],
+        &[0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x00, 0x00]
+    );
+
+    let feaa = Uuid::from_sdp_short_uuid(0xFEAA_u16);
+    let advertisement_data = uid.advertisement_data();
+    assert_eq!(advertisement_data.service_uuids, vec![feaa]);
+    assert_eq!(advertisement_data.service_data.get(&feaa), Some(&frame));
+}
+
+#[test]
+fn test_eddystone_url_compression() {
+    let url = EddystoneUrl::new(-20, "https://www.example.com/path").unwrap();
+    assert_eq!(url.encoded_url()[0], 0x01);
+    assert_eq!(&url.encoded_url()[1..8], b"example");
+    assert_eq!(url.encoded_url()[8], 0x00);
+    assert_eq!(&url.encoded_url()[9..], b"path");
+
+    assert_eq!(
+        EddystoneUrl::new(-20, "ftp://example.com"),
+        Err(EddystoneError::UnsupportedUrlScheme)
+    );
+    assert_eq!(
+        EddystoneUrl::new(-20, "http://a-rather-long-domain-name.org"),
+        Err(EddystoneError::UrlTooLong { length: 26 })
+    );
+}
+
+#[test]
+fn test_eddystone_tlm_frame() {
+    let tlm = EddystoneTlm::new(3000, Some(21.5), 1024, Duration::from_secs(60));
+    assert_eq!(
+        tlm.frame(),
+        vec![0x20, 0x00, 0x0B, 0xB8, 0x15, 0x80, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x02, 0x58,]
+    );
+
+    let tlm = EddystoneTlm::new(0, None, 0, Duration::from_secs(0));
+    assert_eq!(&tlm.frame()[4..6], &[0x80, 0x00]);
+}
+
+#[test]
+fn test_scan_response_budget_is_separate() {
+    let uuid = Uuid::parse_str("6e400001-b5a3-f393-e0a9-e50e24dcca9e").unwrap();
+    let mut advertisement_data = AdvertisementData::default();
+    advertisement_data.service_uuids.push(uuid);
+    let mut scan_response = ScanResponseData {
+        local_name: Some(String::from("bluster")),
+        ..Default::default()
+    };
+    scan_response
+        .manufacturer_data
+        .insert(0xFFFF, vec![0x00; 10]);
+
+    assert_eq!(
+        ad_structure::validate(&advertisement_data.ad_structures(), LEGACY_MAX_LENGTH),
+        Ok(18)
+    );
+    assert_eq!(
+        ad_structure::validate(&scan_response.ad_structures(), LEGACY_MAX_LENGTH),
+        Ok(23)
+    );
+}
@@ -0,0 +1,44 @@
+#![cfg(any(target_os = "linux", target_os = "android"))]
+
+use std::error::Error as _;
+
+use bluster::{Error, ErrorKind};
+
+#[test]
+fn test_dbus_error_kind() {
+    let error = Error::from(dbus::Error::new_custom(
+        "org.bluez.Error.AlreadyExists",
+        "Already Exists",
+    ));
+    assert_eq!(error.kind(), &ErrorKind::AlreadyRegistered);
+    assert_eq!(error.description(), "Already Exists");
+    assert!(error.source().is_some());
+
+    let error = Error::from(dbus::Error::new_custom(
+        "org.freedesktop.DBus.Error.ServiceUnknown",
+        "The name org.bluez was not provided by any .service files",
+    ));
+    assert_eq!(error.kind(), &ErrorKind::BackendDisconnected);
+}
+
+#[test]
+fn test_type_mismatch_matches_invalid_args() {
+    let message = dbus::Message::new_signal("/", "org.example", "Empty").unwrap();
+    let mismatch = message.read1::<u32>().unwrap_err();
+    let error = Error::from(mismatch);
+    let invalid_args = Error::from(dbus::Error::new_custom(
+        "org.freedesktop.DBus.Error.InvalidArgs",
+        "Invalid arguments",
+    ));
+    assert_eq!(error.kind(), invalid_args.kind());
+    assert_eq!(error.kind(), &ErrorKind::InvalidDefinition);
+}
+
+#[test]
+fn test_unknown_dbus_error_is_platform() {
+    let error = Error::from(dbus::Error::new_custom("org.bluez.Error.Failed", "Failed"));
+    assert_eq!(
+        error.kind(),
+        &ErrorKind::Platform("org.bluez.Error.Failed".to_owned())
+    );
+}
@@ -0,0 +1,693 @@
+#![cfg(any(target_os = "linux", target_os = "android"))]
+// `Characteristic` hashes by UUID only, which is all the API needs
+#![allow(clippy::mutable_key_type)]
+
+use async_trait::async_trait;
+use futures::{channel::mpsc, prelude::*};
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use uuid::Uuid;
+
+use bluster::{
+    advertisement::data::{AdvertisementData, SecondaryChannel},
+    gatt::{
+        characteristic::{self, Access, Characteristic, WriteAccess},
+        event::{channel, Event, EventSender, LongValues, Overflow, Response},
+        handler::{self, CharacteristicHandler, Context, WriteKind},
+        service::Service,
+    },
+    BusConfig, ErrorKind, FakeBluez, Peripheral, PeripheralEvent, SdpShortUuid,
+};
+
+// A private dbus-daemon, so tests neither need nor disturb the system bus
+struct TestBus {
+    daemon: Child,
+    socket: PathBuf,
+}
+
+impl TestBus {
+    fn start() -> Option<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let socket = env::temp_dir().join(format!(
+            "bluster-test-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let daemon = spawn_daemon(&socket)?;
+        Some(TestBus { daemon, socket })
+    }
+
+    fn config(&self) -> BusConfig {
+        BusConfig::address(format!("unix:path={}", self.socket.display()))
+    }
+
+    // Kills the daemon and starts another one at the same address, dropping every connection
+    fn restart(&mut self) {
+        self.stop();
+        self.daemon = spawn_daemon(&self.socket).unwrap();
+    }
+
+    fn stop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Returns once the daemon is listening
+fn spawn_daemon(socket: &Path) -> Option<Child> {
+    let mut daemon = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address=1"])
+        .arg(format!("--address=unix:path={}", socket.display()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take()?)
+        .read_line(&mut address)
+        .ok()?;
+    Some(daemon)
+}
+
+// Starts a private bus with a `FakeBluez` on it
+async fn start() -> (TestBus, FakeBluez) {
+    let bus = TestBus::start().expect("dbus-daemon is needed to test against FakeBluez");
+    let fake = FakeBluez::start(&bus.config()).await.unwrap();
+    (bus, fake)
+}
+
+fn characteristic_uuid() -> Uuid {
+    Uuid::from_sdp_short_uuid(0x2A3D_u16)
+}
+
+fn read(sender: &EventSender) -> Option<characteristic::Read> {
+    Some(characteristic::Read(characteristic::Secure::Insecure(
+        sender.clone(),
+    )))
+}
+
+fn write(sender: &EventSender) -> Option<characteristic::Write> {
+    Some(characteristic::Write::WithResponse(
+        characteristic::Secure::Insecure(sender.clone()),
+    ))
+}
+
+fn characteristic(properties: characteristic::Properties) -> Characteristic {
+    Characteristic::new(characteristic_uuid(), properties, None, HashSet::new())
+}
+
+// Registers `characteristic` in a service of its own and returns its path on the fake
+async fn register(
+    bus: &TestBus,
+    fake: &FakeBluez,
+    characteristic: Characteristic,
+) -> (Peripheral, dbus::Path<'static>) {
+    let peripheral = Peripheral::with_bus(bus.config(), None, None)
+        .await
+        .unwrap();
+    let mut characteristics = HashSet::new();
+    characteristics.insert(characteristic);
+    peripheral
+        .add_service(&Service::new(
+            Uuid::from_sdp_short_uuid(0x1234_u16),
+            true,
+            characteristics,
+        ))
+        .unwrap();
+    peripheral.register_gatt().await.unwrap();
+    let path = fake
+        .find_characteristic(&characteristic_uuid())
+        .await
+        .unwrap()
+        .unwrap();
+    (peripheral, path)
+}
+
+#[tokio::test]
+async fn test_fake_bluez_gatt_and_advertising() {
+    let (bus, fake) = start().await;
+
+    let (sender, mut receiver) = channel(1);
+    tokio::spawn(async move {
+        let mut value = b"hi".to_vec();
+        while let Some(event) = receiver.next().await {
+            match event {
+                Event::ReadRequest(read_request) => {
+                    let _ = read_request.response.send(Response::Success(
+                        value[read_request.offset as usize..].to_vec(),
+                    ));
+                }
+                Event::WriteRequest(write_request) => {
+                    value = write_request.data;
+                    let _ = write_request.response.send(Response::Success(vec![]));
+                }
+                Event::NotifySubscribe(notify_subscribe) => {
+                    let notifier = notify_subscribe.notification;
+                    let value = value.clone();
+                    tokio::spawn(async move {
+                        let _ = notifier.notify(value).await;
+                    });
+                }
+                Event::NotifyUnsubscribe | Event::IndicationConfirmed => {}
+            }
+        }
+    });
+
+    let (peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::new(
+            read(&sender),
+            write(&sender),
+            Some(sender),
+            None,
+        )),
+    )
+    .await;
+    assert!(fake.is_powered());
+    assert_eq!(fake.applications().len(), 1);
+    assert_eq!(fake.read_value(&path, 0).await.unwrap(), b"hi".to_vec());
+    fake.write_value(&path, b"hello", 0).await.unwrap();
+    assert_eq!(fake.read_value(&path, 1).await.unwrap(), b"ello".to_vec());
+
+    let mut notifications = fake.start_notify(&path).await.unwrap();
+    assert_eq!(notifications.next().await, Some(b"hello".to_vec()));
+    fake.stop_notify(&path).await.unwrap();
+
+    peripheral
+        .start_advertising(&AdvertisementData::new("hello", &[]))
+        .await
+        .unwrap();
+    assert_eq!(fake.advertisements().len(), 1);
+    peripheral.stop_advertising().await.unwrap();
+    assert!(fake.advertisements().is_empty());
+
+    peripheral.shutdown().await.unwrap();
+    assert!(fake.applications().is_empty());
+}
+
+#[tokio::test]
+async fn test_fake_bluez_response_timeout() {
+    let (bus, fake) = start().await;
+
+    let (sender, mut receiver) = channel(1);
+    // Takes requests but never answers them
+    tokio::spawn(async move {
+        let mut pending = vec![];
+        while let Some(event) = receiver.next().await {
+            pending.push(event);
+        }
+    });
+
+    let (peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::new(
+            read(&sender),
+            None,
+            None,
+            None,
+        ))
+        .with_response_timeout(Duration::from_millis(100), Response::InvalidOffset),
+    )
+    .await;
+    let mut events = peripheral.events();
+
+    let err = fake.read_value(&path, 0).await.unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.InvalidOffset".to_owned())
+    );
+    assert_eq!(
+        events.next().await,
+        Some(PeripheralEvent::ReadTimedOut {
+            uuid: characteristic_uuid()
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_fake_bluez_overflow_rejects() {
+    let (bus, fake) = start().await;
+
+    // Nobody takes requests off the channel, so it's full after the first one
+    let (sender, _receiver) = channel(1);
+    let sender = sender.with_overflow(Overflow::Reject(Response::Busy));
+    let (_peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::new(
+            read(&sender),
+            None,
+            Some(sender.clone()),
+            None,
+        ))
+        .with_response_timeout(Duration::from_millis(100), Response::UnlikelyError),
+    )
+    .await;
+
+    let err = fake.read_value(&path, 0).await.unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.Failed".to_owned())
+    );
+    let err = fake.read_value(&path, 0).await.unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.InProgress".to_owned())
+    );
+    // A refused subscription isn't started
+    let err = fake.start_notify(&path).await.unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.InProgress".to_owned())
+    );
+    assert_eq!(sender.rejected(), 2);
+    assert_eq!(sender.dropped(), 0);
+}
+
+struct ValueHandler {
+    value: Mutex<Vec<u8>>,
+    confirmations: mpsc::UnboundedSender<()>,
+}
+
+#[async_trait]
+impl CharacteristicHandler for ValueHandler {
+    async fn on_read(&self, _ctx: &Context, offset: u16) -> Response {
+        Response::Success(self.value.lock().unwrap()[offset as usize..].to_vec())
+    }
+
+    async fn on_write(&self, _ctx: &Context, data: Vec<u8>, kind: WriteKind) -> Response {
+        assert_eq!(kind, WriteKind::Request);
+        *self.value.lock().unwrap() = data;
+        Response::Success(vec![])
+    }
+
+    async fn on_subscribe(&self, ctx: &Context) {
+        let notifier = ctx.notifier().unwrap().clone();
+        let value = self.value.lock().unwrap().clone();
+        tokio::spawn(async move {
+            let _ = notifier.notify(value).await;
+        });
+    }
+
+    async fn on_indication_confirmed(&self, _ctx: &Context) {
+        let _ = self.confirmations.unbounded_send(());
+    }
+}
+
+// Handlers are only spawned once requests come in, so no runtime is needed to set them
+#[test]
+fn test_handler_outside_runtime() {
+    let (confirmations, _confirmed) = mpsc::unbounded();
+    characteristic(characteristic::Properties::declare(
+        Some(Access::Insecure),
+        None,
+        true,
+        false,
+    ))
+    .with_handler(ValueHandler {
+        value: Mutex::new(vec![]),
+        confirmations,
+    });
+}
+
+#[tokio::test]
+async fn test_fake_bluez_handler() {
+    let (bus, fake) = start().await;
+
+    let (confirmations, mut confirmed) = mpsc::unbounded();
+    let (_peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::declare(
+            Some(Access::Insecure),
+            Some(WriteAccess::WithResponse(Access::Insecure)),
+            false,
+            true,
+        ))
+        .with_handler(ValueHandler {
+            value: Mutex::new(b"hi".to_vec()),
+            confirmations,
+        }),
+    )
+    .await;
+
+    assert_eq!(fake.read_value(&path, 0).await.unwrap(), b"hi".to_vec());
+    fake.write_value(&path, b"hello", 0).await.unwrap();
+    assert_eq!(fake.read_value(&path, 1).await.unwrap(), b"ello".to_vec());
+
+    let mut indications = fake.start_notify(&path).await.unwrap();
+    assert_eq!(indications.next().await, Some(b"hello".to_vec()));
+    fake.confirm(&path).await.unwrap();
+    assert_eq!(confirmed.next().await, Some(()));
+    fake.stop_notify(&path).await.unwrap();
+}
+
+// Passes on how every write was made
+struct WriteKinds(mpsc::UnboundedSender<WriteKind>);
+
+#[async_trait]
+impl CharacteristicHandler for WriteKinds {
+    async fn on_write(&self, _ctx: &Context, _data: Vec<u8>, kind: WriteKind) -> Response {
+        let _ = self.0.unbounded_send(kind);
+        Response::Success(vec![])
+    }
+}
+
+#[tokio::test]
+async fn test_fake_bluez_write_command() {
+    let (bus, fake) = start().await;
+
+    let (kinds, mut written) = mpsc::unbounded();
+    let sender = handler::spawn(WriteKinds(kinds), 1);
+    let (_peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::new(
+            None,
+            Some(characteristic::Write::WithoutResponse(sender)),
+            None,
+            None,
+        )),
+    )
+    .await;
+
+    fake.write_command(&path, b"hi").await.unwrap();
+    assert_eq!(written.next().await, Some(WriteKind::Command));
+    fake.write_value(&path, b"hi", 0).await.unwrap();
+    assert_eq!(written.next().await, Some(WriteKind::Request));
+}
+
+#[tokio::test]
+async fn test_fake_bluez_long_values() {
+    let (bus, fake) = start().await;
+
+    let (sender, mut receiver) = channel(1);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let (writes, mut written) = mpsc::unbounded();
+    let handler_reads = Arc::clone(&reads);
+    tokio::spawn(async move {
+        let value: Vec<u8> = (0..40).collect();
+        while let Some(event) = receiver.next().await {
+            match event {
+                Event::ReadRequest(read_request) => {
+                    assert_eq!(read_request.offset, 0);
+                    handler_reads.fetch_add(1, Ordering::SeqCst);
+                    let _ = read_request.response.send(Response::Success(value.clone()));
+                }
+                Event::WriteRequest(write_request) => {
+                    assert_eq!(write_request.offset, 0);
+                    let _ = writes.unbounded_send(write_request.data);
+                    let _ = write_request.response.send(Response::Success(vec![]));
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let (_peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::new(
+            read(&sender),
+            write(&sender),
+            None,
+            None,
+        ))
+        .with_long_values(LongValues {
+            max_length: 64,
+            settle: Duration::from_millis(50),
+        }),
+    )
+    .await;
+
+    // Later parts of a long read come from the value read at offset 0
+    assert_eq!(fake.read_value(&path, 0).await.unwrap().len(), 40);
+    assert_eq!(
+        fake.read_value(&path, 22).await.unwrap(),
+        (22..40).collect::<Vec<u8>>()
+    );
+    assert!(fake.read_value(&path, 40).await.unwrap().is_empty());
+    assert_eq!(reads.load(Ordering::SeqCst), 1);
+    let err = fake.read_value(&path, 41).await.unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.InvalidOffset".to_owned())
+    );
+
+    // A client that stops partway through doesn't keep the value past `settle`
+    fake.read_value(&path, 0).await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    fake.read_value(&path, 22).await.unwrap();
+    assert_eq!(reads.load(Ordering::SeqCst), 3);
+
+    let long_value: Vec<u8> = (0..50).collect();
+    fake.write_long_value(&path, &long_value, 18).await.unwrap();
+    assert_eq!(written.next().await, Some(long_value.clone()));
+
+    let err = fake
+        .write_long_value(&path, &[0; 70], 18)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.InvalidValueLength".to_owned())
+    );
+    let err = fake.write_value(&path, b"hi", 5).await.unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &ErrorKind::Platform("org.bluez.Error.InvalidOffset".to_owned())
+    );
+
+    fake.write_value(&path, b"hi", 0).await.unwrap();
+    assert_eq!(written.next().await, Some(b"hi".to_vec()));
+
+    // A long write still settling reaches the handler before the client's next write
+    fake.write_long_value(&path, &long_value, 18).await.unwrap();
+    fake.write_value(&path, b"hi", 0).await.unwrap();
+    assert_eq!(written.next().await, Some(long_value));
+    assert_eq!(written.next().await, Some(b"hi".to_vec()));
+}
+
+// Refuses every write
+struct RejectWrites;
+
+#[async_trait]
+impl CharacteristicHandler for RejectWrites {
+    async fn on_write(&self, _ctx: &Context, _data: Vec<u8>, _kind: WriteKind) -> Response {
+        Response::UnlikelyError
+    }
+}
+
+#[tokio::test]
+async fn test_fake_bluez_long_write_rejected() {
+    let (bus, fake) = start().await;
+
+    let (peripheral, path) = register(
+        &bus,
+        &fake,
+        characteristic(characteristic::Properties::declare(
+            None,
+            Some(WriteAccess::WithResponse(Access::Insecure)),
+            false,
+            false,
+        ))
+        .with_long_values(LongValues {
+            max_length: 64,
+            settle: Duration::from_millis(50),
+        })
+        .with_handler(RejectWrites),
+    )
+    .await;
+    let mut events = peripheral.events();
+
+    // Each part is accepted, the refusal comes once they're put back together
+    fake.write_long_value(&path, &[0; 50], 18).await.unwrap();
+    assert_eq!(
+        events.next().await,
+        Some(PeripheralEvent::LongWriteFailed {
+            uuid: characteristic_uuid()
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_fake_bluez_advertisement_instances() {
+    let (bus, fake) = start().await;
+    let peripheral = Peripheral::with_bus(bus.config(), None, None)
+        .await
+        .unwrap();
+
+    let mut advertisements = vec![];
+    for _ in 0..5 {
+        let advertisement = peripheral.new_advertisement().await.unwrap();
+        advertisement
+            .start(&AdvertisementData::new("hello", &[]))
+            .await
+            .unwrap();
+        advertisements.push(advertisement);
+    }
+    assert_eq!(fake.advertisements().len(), 5);
+
+    let err = peripheral.new_advertisement().await.unwrap_err();
+    assert_eq!(err.kind(), &ErrorKind::ResourcesExhausted);
+
+    // Dropping one frees its instance for the next
+    advertisements.pop();
+    peripheral.new_advertisement().await.unwrap();
+
+    // Even when it's rotating payloads in the background
+    let rotating = advertisements.pop().unwrap();
+    rotating
+        .start_rotation(
+            vec![
+                AdvertisementData::new("hello", &[]),
+                AdvertisementData::new("world", &[]),
+            ],
+            Duration::from_millis(10),
+            true,
+        )
+        .await
+        .unwrap();
+    drop(rotating);
+    peripheral.new_advertisement().await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(fake.advertisements().len(), 3);
+}
+
+#[tokio::test]
+async fn test_fake_bluez_restart_advertising_as_broadcast() {
+    let (bus, fake) = start().await;
+    let peripheral = Peripheral::with_bus(bus.config(), None, None)
+        .await
+        .unwrap();
+
+    peripheral
+        .start_advertising(&AdvertisementData::new("hello", &[]))
+        .await
+        .unwrap();
+    peripheral
+        .start_broadcasting(&AdvertisementData::new("hello", &[]))
+        .await
+        .unwrap();
+    assert_eq!(fake.advertisements().len(), 1);
+    assert!(peripheral.is_advertising().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_fake_bluez_extended_advertising_unsupported() {
+    let (bus, fake) = start().await;
+    let peripheral = Peripheral::with_bus(bus.config(), None, None)
+        .await
+        .unwrap();
+
+    // The fake adapter reports no secondary channels
+    let capabilities = peripheral.advertising_capabilities().await.unwrap();
+    assert!(!capabilities.supports_extended_advertising());
+
+    let mut data = AdvertisementData::new("hello", &[]);
+    data.secondary_channel = Some(SecondaryChannel::TwoM);
+    let err = peripheral.start_advertising(&data).await.unwrap_err();
+    assert_eq!(err.kind(), &ErrorKind::NotSupported);
+    assert!(fake.advertisements().is_empty());
+}
+
+#[tokio::test]
+async fn test_fake_bluez_adapter_selection() {
+    let (bus, fake) = start().await;
+
+    let err = Peripheral::with_bus(bus.config(), None, Some("hci1"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), &ErrorKind::AdapterNotFound);
+
+    let peripheral = Peripheral::with_bus(bus.config(), None, Some("hci0"))
+        .await
+        .unwrap();
+    let tenant = peripheral
+        .new_sharing_connection(None, Some(&fake.adapter_path().to_string()))
+        .await
+        .unwrap();
+    tenant.register_gatt().await.unwrap();
+    assert_eq!(fake.applications().len(), 1);
+    let err = peripheral
+        .new_sharing_connection(None, Some("hci1"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), &ErrorKind::AdapterNotFound);
+}
+
+#[tokio::test]
+async fn test_fake_bluez_restart_restores_registrations() {
+    let (bus, fake) = start().await;
+    let peripheral = Peripheral::with_bus(bus.config(), None, None)
+        .await
+        .unwrap();
+    let mut events = peripheral.events();
+    peripheral.register_gatt().await.unwrap();
+    peripheral
+        .start_advertising(&AdvertisementData::new("hello", &[]))
+        .await
+        .unwrap();
+
+    fake.restart().await.unwrap();
+    assert_eq!(events.next().await, Some(PeripheralEvent::BluezStopped));
+    assert_eq!(events.next().await, Some(PeripheralEvent::BluezStarted));
+    for _ in 0..100 {
+        if fake.applications().len() == 1 && fake.advertisements().len() == 1 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(fake.applications().len(), 1);
+    assert_eq!(fake.advertisements().len(), 1);
+}
+
+#[tokio::test]
+async fn test_fake_bluez_reconnect_restores_registrations() {
+    let (mut bus, fake) = start().await;
+    let peripheral = Peripheral::with_bus(bus.config(), None, None)
+        .await
+        .unwrap();
+    peripheral.set_auto_reconnect(true);
+    let mut events = peripheral.events();
+    peripheral.register_gatt().await.unwrap();
+    assert_eq!(fake.applications().len(), 1);
+
+    drop(fake);
+    bus.restart();
+    let fake = FakeBluez::start(&bus.config()).await.unwrap();
+    match events.next().await {
+        Some(PeripheralEvent::ConnectionLost { .. }) => {}
+        event => panic!("Expected ConnectionLost, got {:?}", event),
+    }
+    assert_eq!(
+        events.next().await,
+        Some(PeripheralEvent::ConnectionRestored)
+    );
+    for _ in 0..100 {
+        if fake.applications().len() == 1 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(fake.applications().len(), 1);
+}
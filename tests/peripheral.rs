@@ -1,4 +1,4 @@
-use futures::{channel::mpsc::channel, prelude::*};
+use futures::prelude::*;
 use std::{
     collections::HashSet,
     sync::{atomic, Arc, Mutex},
@@ -8,12 +8,13 @@ use std::{
 use uuid::Uuid;
 
 use bluster::{
+    advertisement::data::AdvertisementData,
     gatt::{
         characteristic,
         characteristic::Characteristic,
         descriptor,
         descriptor::Descriptor,
-        event::{Event, Response},
+        event::{channel, Event, Response},
         service::Service,
     },
     Peripheral, SdpShortUuid,
@@ -104,11 +105,12 @@ async fn it_advertises_gatt() {
                             };
                             count += 1;
                             println!("GATT server notifying \"hi {}\"!", count);
-                            notify_subscribe
-                                .clone()
+                            let notification = notify_subscribe
                                 .notification
-                                .try_send(format!("hi {}", count).into())
-                                .unwrap();
+                                .notify(format!("hi {}", count).into());
+                            if let Err(err) = futures::executor::block_on(notification) {
+                                println!("GATT server failed to notify: {}", err);
+                            }
                             thread::sleep(Duration::from_secs(2));
                         }
                     });
@@ -117,6 +119,9 @@ async fn it_advertises_gatt() {
                     println!("GATT server got a notify unsubscribe!");
                     notifying.store(false, atomic::Ordering::Relaxed);
                 }
+                Event::IndicationConfirmed => {
+                    println!("GATT server got an indication confirmation!");
+                }
             };
         }
     };
@@ -168,7 +173,7 @@ async fn it_advertises_gatt() {
         println!("Peripheral powered on");
         peripheral.register_gatt().await.unwrap();
         peripheral
-            .start_advertising(ADVERTISING_NAME, &[])
+            .start_advertising(&AdvertisementData::new(ADVERTISING_NAME, &[]))
             .await
             .unwrap();
         println!("Peripheral started advertising");